@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mmachine::bits::{MValue, BITNESS, WORD_MASK};
+use mmachine::io::{OutputRecorder, CONSOLE_OUTPUT_PORT};
+use mmachine::machine::read_image;
+use mmachine::microcodes::INSTRUCTION::{self, *};
+use mmachine::microcodes::{instruction_words, DEST_SHIFT, INSTRUCTION_WORDS, OPCODE_SHIFT, REG};
+use mmachine::{Engine, Machine, MachineBuilder, MachineStatus};
+
+/// programs are cut off after this many cycles
+const MAX_CYCLES: u64 = 20000;
+
+fn encode(op: INSTRUCTION, dst: REG) -> Vec<u32> {
+    instruction_words((op as u32) << OPCODE_SHIFT | (dst as u32) << DEST_SHIFT).to_vec()
+}
+
+fn ldcnst(dst: REG, value: u32) -> Vec<u32> {
+    [encode(LDCNST, dst), vec![value]].concat()
+}
+
+/// 300, or the highest word if 300 doesn't fit
+const COUNTDOWN_START: u32 = if BITNESS < 16 { WORD_MASK } else { 300 };
+
+/// Counts a down from COUNTDOWN_START to 1.
+fn countdown() -> Vec<u32> {
+    let loop_start = 3 * (INSTRUCTION_WORDS as u32 + 1);
+    [
+        ldcnst(REG::A, COUNTDOWN_START),
+        ldcnst(REG::B, 1),
+        ldcnst(REG::E, loop_start),
+        encode(SUB, REG::A),
+        encode(JNE, REG::E),
+        encode(HLT, REG::A),
+    ]
+    .concat()
+}
+
+fn build(image: &[u32], engine: Engine) -> Machine {
+    MachineBuilder::new()
+        .image(image)
+        .engine(engine)
+        .device(
+            CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1,
+            Arc::new(OutputRecorder::new()),
+            None,
+        )
+        .build()
+}
+
+fn run(m: &mut Machine) {
+    for _ in 0..MAX_CYCLES {
+        if m.step_cycle() == MachineStatus::Halted {
+            break;
+        }
+    }
+}
+
+fn cycles(image: &[u32]) -> u64 {
+    let mut m = build(image, Engine::Sequential);
+    let mut ret = 0;
+    while ret < MAX_CYCLES && m.step_cycle() == MachineStatus::Running {
+        ret += 1;
+    }
+    ret
+}
+
+/// Reports cycles per second of the sample programs.
+fn sample_programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cycles");
+    group.sample_size(20);
+    let mut programs = vec![("countdown", countdown())];
+    // the sample binaries are assembled for 16 bit words
+    if BITNESS == 16 {
+        programs.push(("hello", read_image("mmbs/hello.mmb").unwrap()));
+    }
+    for (name, image) in programs {
+        group.throughput(Throughput::Elements(cycles(&image)));
+        for engine in [Engine::Threaded, Engine::Sequential] {
+            let id = format!("{}/{:?}", name, engine).to_lowercase();
+            group.bench_function(id, |b| {
+                b.iter_batched_ref(|| build(&image, engine), run, BatchSize::PerIteration)
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Reports instructions per second of the interpreter.
+fn interpreter(c: &mut Criterion) {
+    let image = countdown();
+    let mut group = c.benchmark_group("instructions");
+    let mut interpreter = MachineBuilder::new().image(&image).build_interpreter();
+    interpreter.run();
+    group.throughput(Throughput::Elements(interpreter.instructions()));
+    group.bench_function("countdown/interpreter", |b| {
+        b.iter_batched_ref(
+            || MachineBuilder::new().image(&image).build_interpreter(),
+            |i| i.run(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn mvalue(c: &mut Criterion) {
+    let a = MValue::from_u32(6719);
+    let b = MValue::from_u32(7877);
+    c.bench_function("mvalue add", |bench| bench.iter(|| a.add(&b)));
+    c.bench_function("mvalue set", |bench| bench.iter(|| a.set(&b)));
+}
+
+criterion_group!(benches, sample_programs, interpreter, mvalue);
+criterion_main!(benches);
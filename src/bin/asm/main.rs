@@ -1,9 +1,13 @@
 use std::io::Write;
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use mmachine::microcodes::{INSTRUCTION, OPCODE_SHIFT, REG};
-use mmachine::microcodes::{INSTRUCTION::*, SOURCE_SHIFT};
+use mmachine::bits::{words_to_bytes, WORD_MASK};
+use mmachine::microcodes::{
+    instruction_words, INSTRUCTION, INSTRUCTION_WORDS, OPCODE_SHIFT, REG,
+};
+use mmachine::microcodes::{INSTRUCTION::*, DEST_SHIFT, SOURCE_SHIFT};
 use phf::phf_map;
 use regex::Regex;
 
@@ -40,6 +44,29 @@ static MNEMONICS: phf::Map<&'static str, INSTRUCTION> = phf_map! {
     "load" => LOAD,
     "store" => STORE,
     "ldcnst" => LDCNST,
+    "sti" => STI,
+    "cli" => CLI,
+    "jc" => JC,
+    "jo" => JO,
+    "jz" => JZ,
+    "js" => JS,
+    "imul" => IMUL,
+    "idiv" => IDIV,
+    "jgs" => JGS,
+    "jges" => JGES,
+    "jls" => JLS,
+    "jles" => JLES,
+    "and" => AND,
+    "or" => OR,
+    "xor" => XOR,
+    "not" => NOT,
+    "shl" => SHL,
+    "shr" => SHR,
+    "sar" => SAR,
+    "rol" => ROL,
+    "ror" => ROR,
+    "mod" => MOD,
+    "divmod" => DIVMOD,
 };
 
 static REG_NAMES: phf::Map<&'static str, REG> = phf_map! {
@@ -53,16 +80,35 @@ static REG_NAMES: phf::Map<&'static str, REG> = phf_map! {
     "inst" => REG::INST,
 };
 
-fn populate_labels(statements: &Vec<Statement>, labels: &mut HashMap<String, u16>) {
-    let mut offset: u16 = 0;
+fn populate_labels(statements: &Vec<Statement>, labels: &mut HashMap<String, u32>) {
+    let mut offset: u32 = 0;
     for s in statements {
         match s {
-            Statement::Command(_, _) => offset += 1,
-            Statement::Ldcnst(_, _) => offset += 2,
+            Statement::Command(_, _) => offset += INSTRUCTION_WORDS as u32,
+            Statement::Ldcnst(_, _) => offset += INSTRUCTION_WORDS as u32 + 1,
             Statement::Label(l) => {
                 labels.insert(l.to_string(), offset);
             }
-            Statement::Data(d) => offset += d.len() as u16,
+            Statement::Data(d) => offset += d.len() as u32,
+        }
+    }
+}
+
+/// Maps the address of every instruction to its source line.
+fn populate_lines(statements: &[Statement], lines: &[u32], line_map: &mut BTreeMap<u32, u32>) {
+    let mut offset: u32 = 0;
+    for (s, line) in statements.iter().zip(lines) {
+        match s {
+            Statement::Command(_, _) => {
+                line_map.insert(offset, *line);
+                offset += INSTRUCTION_WORDS as u32;
+            }
+            Statement::Ldcnst(_, _) => {
+                line_map.insert(offset, *line);
+                offset += INSTRUCTION_WORDS as u32 + 1;
+            }
+            Statement::Label(_) => {}
+            Statement::Data(d) => offset += d.len() as u32,
         }
     }
 }
@@ -77,78 +123,84 @@ fn parse_data(tokens: Vec<String>) -> Statement<'static> {
     Statement::Data(data.to_string())
 }
 
-fn parse_text(text: String) -> Vec<Statement<'static>> {
+fn parse_line(dirty_line: &str) -> Option<Statement<'static>> {
+    let line = dirty_line.split(";").collect::<Vec<&str>>()[0]
+        .trim()
+        .to_lowercase();
+    if line.is_empty() {
+        return None;
+    }
+    let mut tokens: Vec<String> = line.split_whitespace().map(|x| x.to_string()).collect();
+    if tokens[0].contains(":") {
+        let mut label_name = tokens[0].clone();
+        label_name.pop();
+        return Some(Statement::Label(label_name));
+    }
+    let temp = tokens.remove(0);
+    if temp == "data" {
+        return Some(parse_data(tokens));
+    }
+    let mnemonic = temp.as_str();
+    let maybe_op_code = MNEMONICS.get(mnemonic);
+    if maybe_op_code.is_none() {
+        panic!("wrong mnemonic: {}", mnemonic);
+    }
+    let op_code = maybe_op_code.unwrap();
+    if *op_code == LDCNST {
+        return Some(Statement::Ldcnst(
+            REG_NAMES.get(&tokens[0]).unwrap(),
+            tokens[1].clone(),
+        ));
+    }
+    let regs: Vec<&REG> = tokens
+        .iter()
+        .map(|x| match REG_NAMES.get(x) {
+            Some(y) => y,
+            None => panic!("wrong reg name: {}", x),
+        })
+        .collect();
+    Some(Statement::Command(op_code, regs))
+}
+
+/// Also records the source line of every statement, counted from 1.
+fn parse_text(text: String, lines: &mut Vec<u32>) -> Vec<Statement<'static>> {
     let mut ret = Vec::new();
-    for dirty_line in text.lines() {
-        let line = dirty_line.split(";").collect::<Vec<&str>>()[0]
-            .trim()
-            .to_lowercase();
-        if line.len() == 0 {
-            continue;
+    for (i, dirty_line) in text.lines().enumerate() {
+        if let Some(statement) = parse_line(dirty_line) {
+            ret.push(statement);
+            lines.push(i as u32 + 1);
         }
-        let mut tokens: Vec<String> = line.split_whitespace().map(|x| x.to_string()).collect();
-        if tokens[0].contains(":") {
-            let mut label_name = tokens[0].clone();
-            label_name.pop();
-            ret.push(Statement::Label(label_name));
-            continue;
-        }
-        let temp = tokens.remove(0);
-        if temp == "data" {
-            ret.push(parse_data(tokens));
-            continue;
-        }
-        let mnemonic = temp.as_str();
-        let maybe_op_code = MNEMONICS.get(mnemonic);
-        if maybe_op_code.is_none() {
-            panic!("wrong mnemonic: {}", mnemonic);
-        }
-        let op_code = maybe_op_code.unwrap();
-        if *op_code == LDCNST {
-            ret.push(Statement::Ldcnst(
-                REG_NAMES.get(&tokens[0]).unwrap(),
-                tokens[1].clone(),
-            ));
-            continue;
-        }
-        let regs: Vec<&REG> = tokens
-            .iter()
-            .map(|x| match REG_NAMES.get(x) {
-                Some(y) => y,
-                None => panic!("wrong reg name: {}", x),
-            })
-            .collect();
-        ret.push(Statement::Command(op_code, regs));
     }
     ret
 }
 
-fn generate_binary(ast: &Vec<Statement>, labels: &HashMap<String, u16>) -> Vec<u16> {
+fn generate_binary(ast: &Vec<Statement>, labels: &HashMap<String, u32>) -> Vec<u32> {
     let mut ret = vec![];
     for s in ast {
-        let mut opcode: u16 = 0;
+        let mut opcode: u32 = 0;
         match s {
             Statement::Command(c, args) => {
-                opcode |= (**c as u16) << OPCODE_SHIFT;
+                opcode |= (**c as u32) << OPCODE_SHIFT;
                 if args.len() == 1 {
-                    if **c == PUSH {
-                        opcode |= (*args[0] as u16) << SOURCE_SHIFT;
+                    if **c == PUSH || **c == INT {
+                        opcode |= (*args[0] as u32) << SOURCE_SHIFT;
                     } else {
-                        opcode |= *args[0] as u16; // destination
+                        opcode |= (*args[0] as u32) << DEST_SHIFT;
                     }
                 } else if args.len() == 2 {
-                    opcode |= (*args[0] as u16) << SOURCE_SHIFT;
-                    opcode |= *args[1] as u16;
+                    opcode |= (*args[0] as u32) << SOURCE_SHIFT;
+                    opcode |= (*args[1] as u32) << DEST_SHIFT;
                 }
-                ret.push(opcode);
+                ret.extend(instruction_words(opcode));
             }
             Statement::Ldcnst(reg, data) => {
-                opcode |= (LDCNST as u16) << OPCODE_SHIFT;
-                opcode |= **reg as u16;
-                ret.push(opcode);
-                let maybe_constant: Result<u16, _> = data.parse();
+                opcode |= (LDCNST as u32) << OPCODE_SHIFT;
+                opcode |= (**reg as u32) << DEST_SHIFT;
+                ret.extend(instruction_words(opcode));
+                let maybe_constant: Result<u64, _> = data.parse();
                 match maybe_constant {
-                    Ok(constant) => ret.push(constant),
+                    Ok(constant) if constant <= WORD_MASK as u64 => ret.push(constant as u32),
+                    Ok(constant) => panic!("constant too large for the word: {}", constant),
                     Err(_) => {
                         let label_location = labels.get(data).unwrap();
                         ret.push(*label_location);
@@ -158,7 +210,7 @@ fn generate_binary(ast: &Vec<Statement>, labels: &HashMap<String, u16>) -> Vec<u
             Statement::Label(_) => {}
             Statement::Data(d) => {
                 for c in d.chars() {
-                    ret.push(c as u16);
+                    ret.push(c as u32);
                 }
             }
         }
@@ -166,16 +218,6 @@ fn generate_binary(ast: &Vec<Statement>, labels: &HashMap<String, u16>) -> Vec<u
     ret
 }
 
-pub fn to_bytes(input: Vec<u16>) -> Vec<u8> {
-    let mut bytes = Vec::with_capacity(2 * input.len());
-
-    for value in input {
-        bytes.extend(&value.to_be_bytes());
-    }
-
-    bytes
-}
-
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -185,15 +227,45 @@ struct Args {
     /// the output binary file
     #[arg(short, long)]
     output: PathBuf,
+
+    /// a file listing the source file, every label and the source line of
+    /// every instruction, read by the debuggers
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+}
+
+fn write_symbols(
+    path: PathBuf,
+    src_file: &Path,
+    labels: &HashMap<String, u32>,
+    line_map: &BTreeMap<u32, u32>,
+) {
+    let mut sorted: Vec<(&String, &u32)> = labels.iter().collect();
+    sorted.sort_by_key(|(name, address)| (**address, *name));
+    let mut f = std::fs::File::create(path).unwrap();
+    let src_file = std::fs::canonicalize(src_file).unwrap();
+    writeln!(f, "source {}", src_file.display()).unwrap();
+    for (name, address) in sorted {
+        writeln!(f, "label {} {}", name, address).unwrap();
+    }
+    for (address, line) in line_map {
+        writeln!(f, "line {} {}", line, address).unwrap();
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let contents = std::fs::read_to_string(args.src_file).unwrap();
+    let contents = std::fs::read_to_string(&args.src_file).unwrap();
     let mut labels = HashMap::new();
-    let ast = parse_text(contents);
+    let mut lines = Vec::new();
+    let ast = parse_text(contents, &mut lines);
     populate_labels(&ast, &mut labels);
     let bin = generate_binary(&ast, &labels);
     let mut f = std::fs::File::create(args.output).unwrap();
-    f.write_all(&to_bytes(bin)).unwrap();
+    f.write_all(&words_to_bytes(&bin)).unwrap();
+    if let Some(path) = args.symbols {
+        let mut line_map = BTreeMap::new();
+        populate_lines(&ast, &lines, &mut line_map);
+        write_symbols(path, &args.src_file, &labels, &line_map);
+    }
 }
@@ -1,171 +1,216 @@
-use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
+use std::path::PathBuf;
 
 use clap::Parser;
-use mmachine::bits::MValue;
-use mmachine::bus::Bus;
-use mmachine::cpu_component::{
-    start_cpu_component, AluComponent, ControlComponent, CpuComponent, CpuComponentArgs,
-    RamComponent, RegisterComponent, RAM_SIZE, REGISTERS_NUM, STACK_POINTER_REG_NUM,
+use mmachine::dap::DapServer;
+use mmachine::debugger::{read_symbols, Debugger, Symbols};
+use mmachine::disk::{BlockDevice, DISK_IRQ_LINE, DISK_PORTS};
+use mmachine::display::{
+    TextDisplay, DEFAULT_DISPLAY_HEIGHT, DEFAULT_DISPLAY_WIDTH, DISPLAY_PORTS,
 };
-use mmachine::microcodes::create_fetch_microcodes;
-use parking_lot::Mutex;
-use std::io::{self, BufRead};
-use std::sync::atomic::AtomicUsize;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use mmachine::gdb::GdbStub;
+use mmachine::io::{ConsoleInput, CONSOLE_INPUT_PORTS, CONSOLE_IRQ_LINE};
+use mmachine::machine::{read_image, DEFAULT_CYCLE_TIMEOUT};
+use mmachine::bits::WORD_MASK;
+use mmachine::cpu_component::PROGRAM_COUNTER_REG_NUM;
+use mmachine::microcodes::INSTRUCTION_WORDS;
+use mmachine::snapshot::Snapshot;
+use mmachine::{Engine, Machine, MachineBuilder, MachineStatus};
+use std::fs::File;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 
-fn run_input(input_tx: Sender<Option<MValue>>, input_req_rx: Receiver<MValue>) {
-    loop {
-        match input_req_rx.recv() {
-            Ok(_) => input_tx.send(None).unwrap(),
-            Err(_) => return,
-        }
-    }
-}
-
-fn run_output(output_rx: Receiver<(MValue, MValue)>) {
-    loop {
-        match output_rx.recv() {
-            Ok((port, value)) => {
-                if port.as_u32() == 1 {
-                    print!("{}", (value.as_u32() as u8) as char);
-                } else {
-                    println!("OUTPUT: port {} value {}", port.as_u32(), value.as_u32());
-                }
-            }
-            Err(_) => return,
-        }
-    }
-}
+/// cycles between redraws of the display
+const DISPLAY_REFRESH_CYCLES: u32 = 1000;
 
-fn load_ram(path: PathBuf) -> Box<[MValue; RAM_SIZE]> {
-    let contents = std::fs::read(path).unwrap();
-    let mut ret = Vec::new();
-    for i in 0..contents.len() / 2 {
-        let x: u32 = (contents[2 * i] as u32) << 8 | contents[2 * i + 1] as u32;
-        ret.push(MValue::from_u32(x));
+fn parse_display_size(s: &str) -> Result<(usize, usize), String> {
+    let (width, height) = s.split_once('x').ok_or("expected WIDTHxHEIGHT")?;
+    let width = width.parse().map_err(|_| "wrong width")?;
+    let height = height.parse().map_err(|_| "wrong height")?;
+    if width == 0 || height == 0 {
+        return Err("the display can't be empty".to_string());
     }
-    ret.resize(RAM_SIZE, MValue::from_u32(0));
-    ret.try_into().unwrap()
+    Ok((width, height))
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// the binary file that will be loaded at 0 at startup
-    bin_file: PathBuf,
+    #[arg(required_unless_present = "resume")]
+    bin_file: Option<PathBuf>,
+
+    /// start from a snapshot saved by --save-snapshot or the debugger
+    #[arg(long, value_name = "SNAPSHOT", conflicts_with = "interpreter")]
+    resume: Option<PathBuf>,
+
+    /// file the state of the machine is saved to when it stops
+    #[arg(long, value_name = "SNAPSHOT", conflicts_with = "interpreter")]
+    save_snapshot: Option<PathBuf>,
+
+    /// start the debugger prompt instead of running
+    #[arg(short = 's', long, default_value_t = false, visible_alias = "step")]
+    debug: bool,
+
+    /// the file written by `asm --symbols`, used by the debugger and the dap
+    /// server
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+
+    /// wait for gdb to connect to this local port and let it drive the machine
+    #[arg(long, value_name = "PORT", conflicts_with = "debug")]
+    gdb: Option<u16>,
+
+    /// wait for an editor to connect to this local port and serve it the debug
+    /// adapter protocol
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["debug", "gdb"])]
+    dap: Option<u16>,
+
+    /// run every component on the main thread instead of a thread each
+    #[arg(long, default_value_t = false)]
+    sequential: bool,
+
+    /// run whole instructions without the microcoded cpu
+    #[arg(long, default_value_t = false, conflicts_with_all = ["debug", "gdb", "dap", "sequential"])]
+    interpreter: bool,
 
-    /// whether to wait for enter to step
-    #[arg(short, long, default_value_t = false)]
-    step: bool,
+    /// milliseconds a cycle may take before it is reported as stuck, 0 waits
+    /// forever
+    #[arg(long, default_value_t = DEFAULT_CYCLE_TIMEOUT.as_millis() as u64)]
+    cycle_timeout: u64,
+
+    /// file fed to the console input instead of stdin
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+
+    /// disk image file used by the block device
+    #[arg(short, long)]
+    disk: Option<PathBuf>,
+
+    /// render a text display of the given size (default 80x25) to the terminal
+    #[arg(long, value_name = "WIDTHxHEIGHT", num_args = 0..=1, value_parser = parse_display_size)]
+    display: Option<Option<(usize, usize)>>,
+
+    /// file the display text is saved to at halt
+    #[arg(long, requires = "display")]
+    display_snapshot: Option<PathBuf>,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let cables = array_init::array_init(|_| AtomicBool::new(false));
-    let bus = Arc::new(Bus::new());
-    let finished = AtomicUsize::new(0);
-    let sent_to_alu = Arc::new(AtomicUsize::new(0));
-    let (clock_tx, clock_rx) = channel();
-    let (alu_tx, alu_rx) = channel();
-    let (alu_clock_tx, alu_clock_rx) = channel();
-    let alu_tx_arc = Arc::new(Mutex::new(alu_tx));
-    let (ctrl_tx, ctrl_rx) = channel();
-    let (output_tx, output_rx) = channel();
-    let (input_tx, input_rx) = channel();
-    let (input_req_tx, input_req_rx) = channel();
-    let (clock_step_tx, clock_step_rx) = channel();
-
-    let mut txs = Vec::new();
-
-    let mut components: Vec<Arc<dyn CpuComponent + Send + Sync>> = vec![
-        Arc::new(RamComponent {
-            memory: load_ram(args.bin_file),
-            memory_address_register: MValue::default(),
-            ram_register: MValue::default(),
-            output_tx: Arc::new(Mutex::new(output_tx)),
-            input_rx: Arc::new(Mutex::new(input_rx)),
-            input_req_tx: Arc::new(Mutex::new(input_req_tx)),
-        }),
-    ];
-    for i in 0..REGISTERS_NUM {
-        let mut start_value: u32 = 0;
-        if i == STACK_POINTER_REG_NUM {
-            start_value = RAM_SIZE as u32 - 1;
+    let image = match &args.bin_file {
+        Some(path) => read_image(path).unwrap(),
+        None => Vec::new(),
+    };
+    let snapshot = args.resume.as_ref().map(|path| Snapshot::load(path).unwrap());
+    let build = |builder: MachineBuilder| {
+        let mut machine = builder.build();
+        if let Some(snapshot) = &snapshot {
+            machine.restore(snapshot).unwrap();
         }
-        components.push(Arc::new(RegisterComponent {
-            value: MValue::from_u32(start_value),
-            reg_num: i,
-            alu_tx: alu_tx_arc.clone(),
-            sent_to_alu: sent_to_alu.clone(),
-        }));
+        machine
+    };
+    let finish = |machine: &Machine| {
+        if let Some(path) = &args.save_snapshot {
+            machine.snapshot().save(path).unwrap();
+        }
+        let report = machine.report().map(str::to_string);
+        (machine.status(), machine.next_address(), report, machine.exit_code())
+    };
+    let engine = if args.sequential {
+        Engine::Sequential
+    } else {
+        Engine::Threaded
+    };
+    let cycle_timeout = match args.cycle_timeout {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    let mut builder = MachineBuilder::new()
+        .image(&image)
+        .engine(engine)
+        .cycle_timeout(cycle_timeout);
+    // stdin is used by the debugger, so it only feeds the console when not debugging
+    let console_input = match &args.input {
+        Some(path) => Some(ConsoleInput::spawn(File::open(path).unwrap())),
+        None if !args.debug => Some(ConsoleInput::spawn(io::stdin())),
+        None => None,
+    };
+    if let Some(input) = console_input {
+        builder = builder.device(CONSOLE_INPUT_PORTS, input, Some(CONSOLE_IRQ_LINE));
+    }
+    if let Some(path) = &args.disk {
+        let disk = BlockDevice::open(path).unwrap();
+        builder = builder.device(DISK_PORTS, disk, Some(DISK_IRQ_LINE));
     }
-    let flags_register = Arc::new(MValue::from_u32(0));
-    let alu = Arc::new(AluComponent {
-        reg_a: MValue::from_u32(0),
-        reg_b: MValue::from_u32(0),
-        flags_reg: flags_register.clone(),
+    let display = args.display.map(|size| {
+        let (width, height) = size.unwrap_or((DEFAULT_DISPLAY_WIDTH, DEFAULT_DISPLAY_HEIGHT));
+        let display = TextDisplay::new(width, height);
+        if args.debug {
+            Arc::new(display)
+        } else {
+            print!("\x1b[2J");
+            Arc::new(display.with_terminal(DISPLAY_REFRESH_CYCLES))
+        }
     });
-    components.push(alu.clone());
-
-    let print_components = components.clone();
-
-    std::thread::scope(|s| {
-        s.spawn(|| {
-            alu.run(alu_rx, alu_clock_tx, ctrl_tx);
-        });
-        s.spawn(move || {
-            run_input(input_tx, input_req_rx);
-        });
-        s.spawn(move || {
-            run_output(output_rx);
-        });
-        for c in components {
-            let (tx, rx) = channel();
-            start_cpu_component(
-                CpuComponentArgs {
-                    cables: &cables,
-                    bus: bus.clone(),
-                    rx: rx,
-                    finished: &finished,
-                    clock_tx: clock_tx.clone(),
-                },
-                c,
-                s,
-            );
-            txs.push(tx);
+    if let Some(display) = &display {
+        builder = builder.device(DISPLAY_PORTS, display.clone(), None);
+    }
+    // the machine is dropped at the end of its block, joining its threads, the
+    // address is the one of the last instruction as pc is already past it
+    let (status, address, report, exit_code) = if args.interpreter {
+        let mut interpreter = builder.build_interpreter();
+        let status = interpreter.run();
+        let pc = interpreter.register(PROGRAM_COUNTER_REG_NUM);
+        let address = pc.wrapping_sub(INSTRUCTION_WORDS as u32) & WORD_MASK;
+        (status, address, None, interpreter.exit_code())
+    } else if args.debug {
+        let mut debugger = Debugger::new(build(builder));
+        if let Some(path) = &args.symbols {
+            debugger = debugger.with_symbols(read_symbols(path).unwrap().labels);
         }
-        let clock = ControlComponent {
-            clock_rx: clock_rx,
-            txs: txs,
-            finished: &finished,
-            alu_clock_rx: alu_clock_rx,
-            sent_to_alu: sent_to_alu.clone(),
-            cables: &cables,
-            bus: bus.clone(),
-            microcode_counter: AtomicUsize::new(0),
-            instruction_register: MValue::from_u32(0),
-            current_microcodes: Arc::new(Mutex::new(create_fetch_microcodes())),
-            clock_step_rx: clock_step_rx,
-            clock_step: args.step,
-            flags_register: flags_register.clone(),
+        debugger.repl(io::stdin().lock(), io::stdout()).unwrap();
+        finish(debugger.machine())
+    } else if let Some(port) = args.dap {
+        let symbols = match &args.symbols {
+            Some(path) => read_symbols(path).unwrap(),
+            None => Symbols::default(),
         };
-        s.spawn(move || {
-            clock.run(ctrl_rx);
-        });
-        let mut line = String::new();
-        let stdin = io::stdin();
-
-        loop {
-            if args.step {
-                stdin.lock().read_line(&mut line).unwrap();
-                for c in &print_components {
-                    c.step_print();
-                }
-                clock_step_tx.send(()).unwrap();
-            }
+        let mut server = DapServer::new(build(builder), symbols);
+        println!("waiting for an editor on 127.0.0.1:{}", port);
+        server.listen(("127.0.0.1", port)).unwrap();
+        finish(server.machine())
+    } else if let Some(port) = args.gdb {
+        let mut stub = GdbStub::new(build(builder));
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        stub.listen(("127.0.0.1", port)).unwrap();
+        finish(stub.machine())
+    } else {
+        let mut machine = build(builder);
+        machine.run();
+        finish(&machine)
+    };
+    if let Some(display) = &display {
+        if !args.debug {
+            display.render(&mut io::stdout()).unwrap();
         }
-    });
+        if let Some(path) = &args.display_snapshot {
+            display.save_snapshot(path).unwrap();
+        }
+    }
+    // a program can't tell its own exit code 1 from these
+    match status {
+        MachineStatus::Faulted(exception) => {
+            eprintln!("\nunhandled exception: {} at {}", exception, address);
+            std::process::exit(1);
+        }
+        MachineStatus::BusError(e) => {
+            eprintln!("\nbus error: {}\n{}", e, report.unwrap_or_default());
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+    println!("\nclock: halt");
+    std::process::exit(exit_code as i32);
 }
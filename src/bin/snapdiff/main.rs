@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use mmachine::snapshot::Snapshot;
+
+/// Prints the differences between two machine snapshots, exits with 1 if there
+/// are any.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    first: PathBuf,
+    second: PathBuf,
+}
+
+fn main() {
+    let args = Args::parse();
+    let first = Snapshot::load(&args.first).unwrap();
+    let second = Snapshot::load(&args.second).unwrap();
+    let diff = first.diff(&second);
+    for line in &diff {
+        println!("{}", line);
+    }
+    std::process::exit(if diff.is_empty() { 0 } else { 1 });
+}
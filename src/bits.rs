@@ -1,117 +1,272 @@
-use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+#[cfg(all(feature = "word8", feature = "word32"))]
+compile_error!("the word8 and word32 features select different word widths");
+
+#[cfg(feature = "word8")]
+pub const BITNESS: usize = 8;
+#[cfg(not(any(feature = "word8", feature = "word32")))]
 pub const BITNESS: usize = 16;
+#[cfg(feature = "word32")]
+pub const BITNESS: usize = 32;
+
+pub const WORD_MASK: u32 = u32::MAX >> (32 - BITNESS);
+/// the highest bit of a word, set for negative values
+pub const SIGN_MASK: u32 = 1 << (BITNESS - 1);
+/// size of a word in binary and disk image files
+pub const WORD_BYTES: usize = BITNESS / 8;
+
+/// Interprets a word as a two's complement number.
+pub fn sign_extend(word: u32) -> i32 {
+    ((word << (32 - BITNESS)) as i32) >> (32 - BITNESS)
+}
+
+/// Whether a signed result fits into a word.
+fn fits_signed(value: i64) -> bool {
+    let limit = 1i64 << (BITNESS - 1);
+    (-limit..limit).contains(&value)
+}
+
+/// Converts big endian words of an image file into words.
+pub fn words_from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(WORD_BYTES)
+        .map(|w| w.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+        .collect()
+}
+
+/// Converts words into big endian words of an image file.
+pub fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|w| w.to_be_bytes()[4 - WORD_BYTES..].to_vec())
+        .collect()
+}
 
+/// A single bit of an MValue, behaves like an AtomicBool.
+pub struct Bit<'a> {
+    val: &'a AtomicU32,
+    mask: u32,
+}
+
+impl Bit<'_> {
+    pub fn load(&self, order: Ordering) -> bool {
+        self.val.load(order) & self.mask != 0
+    }
+
+    pub fn store(&self, bit: bool, order: Ordering) {
+        if bit {
+            self.val.fetch_or(self.mask, order);
+        } else {
+            self.val.fetch_and(!self.mask, order);
+        }
+    }
+}
+
+/// A word packed into one atomic, bit i is the i-th lowest bit.
 #[derive(Default, Debug)]
 pub struct MValue {
-    val: [AtomicBool; BITNESS],
+    val: AtomicU32,
 }
 
 impl MValue {
-    pub fn bit(&self, i: usize) -> &AtomicBool {
-        &self.val[i]
+    pub fn bit(&self, i: usize) -> Bit<'_> {
+        debug_assert!(i < BITNESS);
+        Bit {
+            val: &self.val,
+            mask: 1 << i,
+        }
     }
 
     pub fn set(&self, other: &MValue) {
-        for i in 0..BITNESS {
-            self.val[i].store(other.val[i].load(SeqCst), SeqCst);
-        }
+        self.set_u32(other.as_u32());
+    }
+
+    pub fn set_u32(&self, num: u32) {
+        self.val.store(num & WORD_MASK, SeqCst);
     }
 
     pub fn as_u32(&self) -> u32 {
-        let mut ret = 0;
-        let mut two = 1;
-        for i in 0..BITNESS {
-            ret += (self.val[i].load(SeqCst) as u32) * two;
-            two *= 2;
-        }
-        ret
+        self.val.load(SeqCst)
+    }
+
+    pub fn as_i32(&self) -> i32 {
+        sign_extend(self.as_u32())
     }
 
     pub fn as_string(&self) -> String {
-        let mut ret = String::new();
-        for i in 0..BITNESS {
-            ret += if self.val[i].load(SeqCst) { "1" } else { "0" };
-        }
-        ret.chars().rev().collect::<String>()
+        format!("{:0>1$b}", self.as_u32(), BITNESS)
     }
 
     pub fn from_u32(num: u32) -> Self {
-        let ret = MValue {
-            val: array_init::array_init(|_| AtomicBool::new(false)),
-        };
-        let mut two = 1;
-        for i in 0..BITNESS {
-            ret.val[i].store((two & num) != 0, SeqCst);
-            two *= 2;
+        MValue {
+            val: AtomicU32::new(num & WORD_MASK),
         }
-        ret
     }
 
-    pub fn add(&self, other: &MValue) {
-        let result = MValue::from_u32(0);
-        let carry = AtomicBool::new(false);
+    /// Returns the carry out of the highest bit.
+    pub fn add(&self, other: &MValue) -> bool {
+        let result = MValue::default();
+        let mut carry = false;
 
         for i in 0..BITNESS {
-            let a_bit = self.val[i].load(SeqCst);
-            let b_bit = other.val[i].load(SeqCst);
+            let a_bit = self.bit(i).load(SeqCst);
+            let b_bit = other.bit(i).load(SeqCst);
 
             // Calculate the sum of a_bit, b_bit, and the previous carry.
-            let sum = (a_bit ^ b_bit) ^ carry.load(SeqCst);
-
-            // Set the result bit to the sum.
-            result.val[i].store(sum, SeqCst);
+            result.bit(i).store(a_bit ^ b_bit ^ carry, SeqCst);
 
             // Calculate the new carry.
-            carry.store((a_bit & b_bit) | (a_bit & carry.load(SeqCst)) | (b_bit & carry.load(SeqCst)), SeqCst);
+            carry = (a_bit & b_bit) | (a_bit & carry) | (b_bit & carry);
         }
         self.set(&result);
+        carry
     }
 
-    pub fn sub(&self, other: &MValue) {
-        let result = MValue::from_u32(0);
-        let borrow = AtomicBool::new(false);
+    /// Returns whether it had to borrow, i.e. other was greater.
+    pub fn sub(&self, other: &MValue) -> bool {
+        let result = MValue::default();
+        let mut borrow = false;
 
         for i in 0..BITNESS {
-            let a_bit = self.val[i].load(SeqCst);
-            let b_bit = other.val[i].load(SeqCst);
+            let a_bit = self.bit(i).load(SeqCst);
+            let b_bit = other.bit(i).load(SeqCst);
 
             // Calculate the difference of a_bit, b_bit, and the previous borrow.
-            let diff = (a_bit ^ b_bit) ^ borrow.load(SeqCst);
-
-            // Set the result bit to the difference.
-            result.val[i].store(diff, SeqCst);
+            result.bit(i).store(a_bit ^ b_bit ^ borrow, SeqCst);
 
             // Calculate the new borrow.
-            borrow.store((!a_bit & b_bit) | ((!a_bit | b_bit) & borrow.load(SeqCst)), SeqCst);
+            borrow = (!a_bit & b_bit) | ((!a_bit | b_bit) & borrow);
         }
         self.set(&result);
+        borrow
     }
 
-    pub fn mul(&self, other: &MValue) {
-        let my_val = self.as_u32();
-        let other_val = other.as_u32();
-        let ret = (my_val * other_val) % (1 << BITNESS);
-        self.set(&MValue::from_u32(ret));
+    /// Returns whether the product didn't fit into a word.
+    pub fn mul(&self, other: &MValue) -> bool {
+        let my_val = self.as_u32() as u64;
+        let other_val = other.as_u32() as u64;
+        let product = my_val * other_val;
+        self.set_u32(product as u32);
+        product > WORD_MASK as u64
     }
 
+    /// Dividing by 0 gives 0, the zero divisor flag reports it.
     pub fn div(&self, other: &MValue) {
+        self.set_u32(self.as_u32().checked_div(other.as_u32()).unwrap_or(0));
+    }
+
+    /// The unsigned remainder of dividing by other, the value itself for 0.
+    pub fn rem(&self, other: &MValue) {
         let my_val = self.as_u32();
-        let other_val = other.as_u32();
-        let ret = my_val / other_val;
-        self.set(&MValue::from_u32(ret));
+        self.set_u32(my_val.checked_rem(other.as_u32()).unwrap_or(my_val));
+    }
+
+    /// Signed multiplication, returns whether the product didn't fit into a word.
+    pub fn imul(&self, other: &MValue) -> bool {
+        let product = self.as_i32() as i64 * other.as_i32() as i64;
+        self.set_u32(product as u32);
+        !fits_signed(product)
+    }
+
+    /// Signed division rounding towards zero, returns whether the quotient
+    /// didn't fit into a word, which only happens for the lowest value / -1.
+    /// Dividing by 0 gives 0.
+    pub fn idiv(&self, other: &MValue) -> bool {
+        let quotient = (self.as_i32() as i64).checked_div(other.as_i32() as i64).unwrap_or(0);
+        self.set_u32(quotient as u32);
+        !fits_signed(quotient)
+    }
+
+    /// Sets every bit i of the word to f(i), f reads the bits from before.
+    fn map_bits(&self, f: impl Fn(usize) -> bool) {
+        let result = MValue::default();
+        for i in 0..BITNESS {
+            result.bit(i).store(f(i), SeqCst);
+        }
+        self.set(&result);
+    }
+
+    pub fn and(&self, other: &MValue) {
+        self.map_bits(|i| self.bit(i).load(SeqCst) & other.bit(i).load(SeqCst));
+    }
+
+    pub fn or(&self, other: &MValue) {
+        self.map_bits(|i| self.bit(i).load(SeqCst) | other.bit(i).load(SeqCst));
+    }
+
+    pub fn xor(&self, other: &MValue) {
+        self.map_bits(|i| self.bit(i).load(SeqCst) ^ other.bit(i).load(SeqCst));
+    }
+
+    pub fn not(&self) {
+        self.map_bits(|i| !self.bit(i).load(SeqCst));
+    }
+
+    /// Shifts left by other, shifting by BITNESS or more gives 0.
+    pub fn shl(&self, other: &MValue) {
+        let amount = other.as_u32() as usize;
+        self.map_bits(|i| i >= amount && self.bit(i - amount).load(SeqCst));
+    }
+
+    /// Logical shift right, shifting by BITNESS or more gives 0.
+    pub fn shr(&self, other: &MValue) {
+        let amount = other.as_u32() as usize;
+        self.map_bits(|i| amount < BITNESS - i && self.bit(i + amount).load(SeqCst));
+    }
+
+    /// Arithmetic shift right, fills with the sign bit.
+    pub fn sar(&self, other: &MValue) {
+        let amount = (other.as_u32() as usize).min(BITNESS - 1);
+        self.map_bits(|i| self.bit((i + amount).min(BITNESS - 1)).load(SeqCst));
+    }
+
+    /// Rotates left by other modulo BITNESS.
+    pub fn rol(&self, other: &MValue) {
+        let amount = other.as_u32() as usize % BITNESS;
+        self.map_bits(|i| self.bit((i + BITNESS - amount) % BITNESS).load(SeqCst));
+    }
+
+    /// Rotates right by other modulo BITNESS.
+    pub fn ror(&self, other: &MValue) {
+        let amount = other.as_u32() as usize % BITNESS;
+        self.map_bits(|i| self.bit((i + amount) % BITNESS).load(SeqCst));
     }
 }
 
-impl Clone for MValue {
-    fn clone(&self) -> Self {
-        let ret = MValue::from_u32(0);
-        ret.set(&self);
-        ret
+/// The flags register. It never goes on the bus, so unlike an MValue it keeps
+/// bits above the word width.
+#[derive(Default, Debug)]
+pub struct Flags {
+    val: AtomicU32,
+}
+
+impl Flags {
+    pub fn bit(&self, i: usize) -> Bit<'_> {
+        Bit {
+            val: &self.val,
+            mask: 1 << i,
+        }
+    }
+
+    pub fn set_u32(&self, num: u32) {
+        self.val.store(num, SeqCst);
     }
 
-    fn clone_from(&mut self, _source: &Self) {
-        todo!()
+    pub fn as_u32(&self) -> u32 {
+        self.val.load(SeqCst)
+    }
+
+    pub fn from_u32(num: u32) -> Self {
+        Flags {
+            val: AtomicU32::new(num),
+        }
+    }
+}
+
+impl Clone for MValue {
+    fn clone(&self) -> Self {
+        MValue::from_u32(self.as_u32())
     }
 }
@@ -1,33 +1,100 @@
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use crate::bits::MValue;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
+/// A cycle whose cables can't drive the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// a component reads the bus but none writes it
+    NoWriter,
+    /// more than one component writes the bus, holds their number
+    MultipleWriters(usize),
+    /// the cycle didn't finish within the cycle timeout
+    Timeout,
+    /// a component thread has ended, e.g. because it panicked
+    Disconnected,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::NoWriter => write!(f, "the bus is read but not written"),
+            BusError::MultipleWriters(n) => write!(f, "the bus is written by {} components", n),
+            BusError::Timeout => write!(f, "the cycle timed out"),
+            BusError::Disconnected => write!(f, "a component has stopped"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BusState {
+    value: u32,
+    written: bool,
+    timeout: Option<Duration>,
+    timed_out: bool,
+    closed: bool,
+}
+
+/// Carries one value per cycle from its writer to any number of readers,
+/// readers wait until the value of the current cycle has been written. A
+/// reader waiting longer than the cycle timeout gives up and keeps its value,
+/// as do all readers once the bus is closed.
+#[derive(Default)]
 pub struct Bus {
-    value: MValue,
-    rx: Mutex<Receiver<()>>,
-    tx: Mutex<Sender<()>>,
+    state: Mutex<BusState>,
+    written: Condvar,
 }
 
 impl Bus {
+    /// Forgets the value of the last cycle, called before the components step.
+    pub fn start_cycle(&self, timeout: Option<Duration>) {
+        let mut state = self.state.lock();
+        state.written = false;
+        state.timeout = timeout;
+        state.timed_out = false;
+    }
+
+    /// Whether a reader gave up during this cycle.
+    pub fn timed_out(&self) -> bool {
+        self.state.lock().timed_out
+    }
+
     pub fn write_from(&self, val: &MValue) {
-        self.value.set(&val);
-        let tx = self.tx.lock();
-        tx.send(()).unwrap();
+        let mut state = self.state.lock();
+        state.value = val.as_u32();
+        state.written = true;
+        self.written.notify_all();
     }
 
     pub fn read_into(&self, val: &MValue) {
-        let rx = self.rx.lock();
-        rx.recv().unwrap();
-        val.set(&self.value);
+        let mut state = self.state.lock();
+        let deadline = state.timeout.map(|t| Instant::now() + t);
+        while !state.written {
+            if state.closed {
+                return;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.written.wait_until(&mut state, deadline).timed_out() {
+                        state.timed_out = true;
+                        return;
+                    }
+                }
+                None => self.written.wait(&mut state),
+            }
+        }
+        val.set_u32(state.value);
+    }
+
+    /// Wakes every waiting reader for good, called when the machine is dropped.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.written.notify_all();
     }
 
     pub fn new() -> Self {
-        let (tx, rx) = channel();
-        Bus {
-            value: MValue::from_u32(0),
-            rx: Mutex::new(rx),
-            tx: Mutex::new(tx),
-        }
+        Self::default()
     }
 }
@@ -1,15 +1,28 @@
 use parking_lot::Mutex;
 use std::sync::atomic::Ordering::SeqCst;
 
-use crate::bits::{MValue, BITNESS};
-use crate::bus::Bus;
-use crate::microcodes::{create_microcodes, Microcodes};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::mpsc::{Receiver, Sender};
+use crate::bits::{Flags, MValue, BITNESS, SIGN_MASK};
+use crate::bus::{Bus, BusError};
+use crate::interrupts::{Exception, InterruptController};
+use crate::io::PortMap;
+use crate::microcodes::{
+    create_irq_microcodes, create_microcodes, instruction_exception, shift_in_word, Microcodes,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::ops::Range;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 pub const REGISTERS_NUM: usize = 8;
-pub const RAM_SIZE: usize = 1 << BITNESS;
+/// wider words don't widen the memory past 1M words
+pub const ADDRESS_BITS: usize = if BITNESS < 20 { BITNESS } else { 20 };
+pub const RAM_SIZE: usize = 1 << ADDRESS_BITS;
+pub const INTERRUPT_VECTORS_NUM: usize = 64;
+/// 0x200 words below the end of the memory, in the middle of smaller memories
+pub const INTERRUPT_VECTOR_TABLE: usize =
+    RAM_SIZE - if RAM_SIZE < 0x400 { RAM_SIZE / 2 } else { 0x200 };
 
 #[derive(PartialEq, Eq, Hash, FromPrimitive)]
 pub enum ControlCable {
@@ -18,17 +31,61 @@ pub enum ControlCable {
     RamIn,
     RamOut,
     MemoryIsIO,
-    AddMul,
-    SubDiv,
+    /// the bits of the AluOp put on the bus by AluOut
+    AluSelect0,
+    AluSelect1,
+    AluSelect2,
+    AluSelect3,
+    AluSelect4,
     AluOut,
 
     Interrupt,
+    InterruptEnable,
+    InterruptDisable,
+    InterruptAcknowledge,
+    /// put the vector of the exception on the bus
+    DivideErrorVector,
+    InvalidOpcodeVector,
 
     RegBase,
 }
 
 use crate::{decode, ControlCable::*};
 
+/// The operation of the alu, selected by the AluSelect cables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Imul,
+    Idiv,
+    And,
+    Or,
+    Xor,
+    /// only uses a
+    Not,
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+    Mod,
+    /// the remainder of the last Div
+    Remainder,
+}
+
+pub const ALU_SELECT_BITS: usize = 5;
+
+/// The AluSelect cables selecting the operation.
+pub fn alu_select(op: AluOp) -> Vec<usize> {
+    (0..ALU_SELECT_BITS)
+        .filter(|bit| (op as usize) & (1 << bit) != 0)
+        .map(|bit| AluSelect0 as usize + bit)
+        .collect()
+}
+
 pub const CONTROL_CABLES_SIZE: usize =
     std::mem::variant_count::<ControlCable>() + REGISTERS_NUM * 4 - 1;
 pub const INSTRUCTION_REG_NUM: usize = REGISTERS_NUM - 1;
@@ -39,17 +96,30 @@ pub type ControlCables = [AtomicBool; CONTROL_CABLES_SIZE];
 
 pub const EQUAL_BIT_NUM: usize = 0;
 pub const GREATER_BIT_NUM: usize = 1;
+pub const INTERRUPT_ENABLE_BIT_NUM: usize = 2;
+/// the last alu operation carried out of or borrowed into the highest bit
+pub const CARRY_BIT_NUM: usize = 3;
+/// the signed result of the last alu operation didn't fit into a word
+pub const OVERFLOW_BIT_NUM: usize = 4;
+pub const ZERO_BIT_NUM: usize = 5;
+/// the highest bit of the last alu result
+pub const SIGN_BIT_NUM: usize = 6;
+/// a is greater than b as signed numbers
+pub const SIGNED_GREATER_BIT_NUM: usize = 7;
+/// b is 0, divisions raise a divide error
+pub const ZERO_DIVISOR_BIT_NUM: usize = 8;
 
 pub trait ControlCablesExt {
     fn reset(&self);
     fn load(&self, c: ControlCable) -> bool;
     fn store(&self, val: bool, c: ControlCable);
+    fn alu_op(&self) -> AluOp;
 }
 
 impl ControlCablesExt for ControlCables {
     fn reset(&self) {
-        for i in 0..CONTROL_CABLES_SIZE {
-            self[i].store(false, SeqCst);
+        for cable in self {
+            cable.store(false, SeqCst);
         }
     }
     fn load(&self, c: ControlCable) -> bool {
@@ -59,41 +129,71 @@ impl ControlCablesExt for ControlCables {
     fn store(&self, val: bool, c: ControlCable) {
         self[c as usize].store(val, SeqCst);
     }
+
+    /// An invalid selection falls back to AluOp::Add.
+    fn alu_op(&self) -> AluOp {
+        let select = (0..ALU_SELECT_BITS)
+            .filter(|bit| self[AluSelect0 as usize + bit].load(SeqCst))
+            .fold(0, |acc, bit| acc | 1 << bit);
+        num::FromPrimitive::from_usize(select).unwrap_or(AluOp::Add)
+    }
 }
 
-pub struct CpuComponentArgs<'a> {
-    pub cables: &'a ControlCables,
+pub struct CpuComponentArgs {
+    pub cables: Arc<ControlCables>,
     pub bus: Arc<Bus>,
     pub rx: Receiver<()>,
-    pub finished: &'a AtomicUsize,
+    pub finished: Arc<AtomicUsize>,
     pub clock_tx: Sender<()>,
 }
 
 pub trait CpuComponent {
     fn step(&self, bus: Arc<Bus>, cables: &ControlCables);
     fn step_print(&self);
+    /// Whether the component puts a value on the bus with these cables.
+    fn writes_bus(&self, cables: &ControlCables) -> bool;
+    /// Whether the component takes the value on the bus with these cables.
+    fn reads_bus(&self, cables: &ControlCables) -> bool;
+}
+
+/// The component writing the bus with these cables, checking that there is at
+/// most one writer and that the bus isn't read without one.
+pub fn bus_writer(
+    components: &[Arc<dyn CpuComponent + Send + Sync>],
+    cables: &ControlCables,
+) -> Result<Option<usize>, BusError> {
+    let writers: Vec<usize> = (0..components.len())
+        .filter(|i| components[*i].writes_bus(cables))
+        .collect();
+    match writers[..] {
+        [] if components.iter().any(|c| c.reads_bus(cables)) => Err(BusError::NoWriter),
+        [] => Ok(None),
+        [writer] => Ok(Some(writer)),
+        _ => Err(BusError::MultipleWriters(writers.len())),
+    }
 }
 
 pub fn start_cpu_component<
-    'a,
     T: CpuComponent + std::marker::Send + std::marker::Sync + ?Sized + 'static,
 >(
-    args: CpuComponentArgs<'a>,
+    args: CpuComponentArgs,
     component: Arc<T>,
-    scope: &'a std::thread::Scope<'a, '_>,
-) {
-    scope.spawn(move || loop {
+) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
         match args.rx.recv() {
             Ok(_) => {
                 component.step(args.bus.clone(), &args.cables);
                 args.finished.fetch_add(1, SeqCst);
-                args.clock_tx.send(()).unwrap();
+                // the machine is gone after a cycle that timed out
+                if args.clock_tx.send(()).is_err() {
+                    return;
+                }
             }
             Err(_) => {
                 return;
             }
         }
-    });
+    })
 }
 
 pub struct RegisterComponent {
@@ -119,8 +219,12 @@ pub fn reg_dec(reg_num: usize) -> usize {
     RegBase as usize + 4 * reg_num + 3
 }
 
-impl<'a> CpuComponent for RegisterComponent {
+impl CpuComponent for RegisterComponent {
     fn step(&self, bus: Arc<Bus>, cables: &ControlCables) {
+        // written first, a register moved to itself reads its own value
+        if cables[reg_out(self.reg_num)].load(SeqCst) {
+            bus.write_from(&self.value);
+        }
         if cables[reg_in(self.reg_num)].load(SeqCst) {
             bus.read_into(&self.value);
             {
@@ -129,28 +233,40 @@ impl<'a> CpuComponent for RegisterComponent {
                 self.sent_to_alu.fetch_add(1, SeqCst);
             }
         }
-        if cables[reg_out(self.reg_num)].load(SeqCst) {
-            bus.write_from(&self.value);
-        }
-        if cables[reg_inc(self.reg_num)].load(SeqCst) {
+        let inc = cables[reg_inc(self.reg_num)].load(SeqCst);
+        let dec = cables[reg_dec(self.reg_num)].load(SeqCst);
+        if inc {
             self.value.add(&MValue::from_u32(1));
         }
-        if cables[reg_dec(self.reg_num)].load(SeqCst) {
+        if dec {
             self.value.sub(&MValue::from_u32(1));
         }
+        if (inc || dec) && self.reg_num < 2 {
+            // keep the alu copies of a and b in sync
+            let lock = self.alu_tx.lock();
+            lock.send((self.reg_num, self.value.clone())).unwrap();
+            self.sent_to_alu.fetch_add(1, SeqCst);
+        }
+    }
+
+    fn writes_bus(&self, cables: &ControlCables) -> bool {
+        cables[reg_out(self.reg_num)].load(SeqCst)
+    }
+
+    fn reads_bus(&self, cables: &ControlCables) -> bool {
+        cables[reg_in(self.reg_num)].load(SeqCst)
     }
 
     fn step_print(&self) {
-        let mut reg_name: String = self.reg_num.to_string();
-        if self.reg_num == PROGRAM_COUNTER_REG_NUM {
-            reg_name = "pc".to_string();
+        let reg_name = if self.reg_num == PROGRAM_COUNTER_REG_NUM {
+            "pc".to_string()
         } else if self.reg_num == STACK_POINTER_REG_NUM {
-            reg_name = "sp".to_string();
+            "sp".to_string()
         } else if self.reg_num == INSTRUCTION_REG_NUM {
-            reg_name = "ir".to_string();
+            "ir".to_string()
         } else {
-            reg_name = ((self.reg_num as u8 + 97) as char).to_string();
-        }
+            ((self.reg_num as u8 + 97) as char).to_string()
+        };
         println!("reg {reg_name}: {}", self.value.as_u32());
     }
 }
@@ -158,7 +274,9 @@ impl<'a> CpuComponent for RegisterComponent {
 pub struct AluComponent {
     pub reg_a: MValue,
     pub reg_b: MValue,
-    pub flags_reg: Arc<MValue>,
+    /// set by every Div, so a quotient and its remainder can be read in two steps
+    pub remainder: MValue,
+    pub flags_reg: Arc<Flags>,
 }
 
 impl AluComponent {
@@ -168,47 +286,127 @@ impl AluComponent {
         alu_clock_tx: Sender<()>,
         ctrl_tx: Sender<MValue>,
     ) {
-        loop {
-            let (reg_num, mvalue) = reg_rx.recv().unwrap();
-            if reg_num == 0 {
-                self.reg_a.set(&mvalue);
-            }
-            if reg_num == 1 {
-                self.reg_b.set(&mvalue);
+        while let Ok((reg_num, mvalue)) = reg_rx.recv() {
+            self.latch(reg_num, &mvalue);
+            if reg_num == INSTRUCTION_REG_NUM && ctrl_tx.send(mvalue).is_err() {
+                return;
             }
-            if reg_num == INSTRUCTION_REG_NUM {
-                ctrl_tx.send(mvalue).unwrap();
+            if alu_clock_tx.send(()).is_err() {
+                return;
             }
-            self.flags_reg.bit(EQUAL_BIT_NUM).store(self.reg_a.as_u32() == self.reg_b.as_u32(), SeqCst);
-            self.flags_reg.bit(GREATER_BIT_NUM).store(self.reg_a.as_u32() > self.reg_b.as_u32(), SeqCst);
-            alu_clock_tx.send(()).unwrap();
         }
     }
+
+    /// Copies a value written to register a or b into the alu.
+    pub fn latch(&self, reg_num: usize, mvalue: &MValue) {
+        if reg_num == 0 {
+            self.reg_a.set(mvalue);
+        }
+        if reg_num == 1 {
+            self.reg_b.set(mvalue);
+        }
+        self.update_flags();
+    }
+
+    pub fn update_flags(&self) {
+        self.flags_reg.bit(EQUAL_BIT_NUM).store(self.reg_a.as_u32() == self.reg_b.as_u32(), SeqCst);
+        self.flags_reg.bit(GREATER_BIT_NUM).store(self.reg_a.as_u32() > self.reg_b.as_u32(), SeqCst);
+        self.flags_reg
+            .bit(SIGNED_GREATER_BIT_NUM)
+            .store(self.reg_a.as_i32() > self.reg_b.as_i32(), SeqCst);
+        self.flags_reg.bit(ZERO_DIVISOR_BIT_NUM).store(self.reg_b.as_u32() == 0, SeqCst);
+    }
 }
 
 impl CpuComponent for AluComponent {
     fn step(&self, bus: Arc<Bus>, cables: &ControlCables) {
+        if cables.load(InterruptEnable) {
+            self.flags_reg.bit(INTERRUPT_ENABLE_BIT_NUM).store(true, SeqCst);
+        }
+        if cables.load(InterruptDisable) {
+            self.flags_reg.bit(INTERRUPT_ENABLE_BIT_NUM).store(false, SeqCst);
+        }
         if cables.load(AluOut) {
             let ret = self.reg_a.clone();
-            if cables.load(AddMul) {
-                // multiplication or division
-                if cables.load(SubDiv) {
-                    // division
-                    ret.div(&self.reg_b);
-                } else {
-                    // multiplication
-                    ret.mul(&self.reg_b);
+            let b = &self.reg_b;
+            let a_sign = self.reg_a.as_u32() & SIGN_MASK;
+            let b_sign = b.as_u32() & SIGN_MASK;
+            let (carry, overflow) = match cables.alu_op() {
+                AluOp::Add => {
+                    let carry = ret.add(b);
+                    (carry, a_sign == b_sign && ret.as_u32() & SIGN_MASK != a_sign)
                 }
-            } else {
-                // addition or subtraction
-                if cables.load(SubDiv) {
-                    // subtraction
-                    ret.sub(&self.reg_b);
-                } else {
-                    // addition
-                    ret.add(&self.reg_b);
+                AluOp::Sub => {
+                    let carry = ret.sub(b);
+                    (carry, a_sign != b_sign && ret.as_u32() & SIGN_MASK != a_sign)
                 }
-            }
+                AluOp::Mul => {
+                    let overflow = ret.mul(b);
+                    (overflow, overflow)
+                }
+                AluOp::Div => {
+                    self.remainder.set(&ret);
+                    self.remainder.rem(b);
+                    ret.div(b);
+                    (false, false)
+                }
+                AluOp::Mod => {
+                    ret.rem(b);
+                    (false, false)
+                }
+                AluOp::Remainder => {
+                    ret.set(&self.remainder);
+                    (false, false)
+                }
+                AluOp::Imul => {
+                    let overflow = ret.imul(b);
+                    (overflow, overflow)
+                }
+                AluOp::Idiv => {
+                    let overflow = ret.idiv(b);
+                    (overflow, overflow)
+                }
+                AluOp::And => {
+                    ret.and(b);
+                    (false, false)
+                }
+                AluOp::Or => {
+                    ret.or(b);
+                    (false, false)
+                }
+                AluOp::Xor => {
+                    ret.xor(b);
+                    (false, false)
+                }
+                AluOp::Not => {
+                    ret.not();
+                    (false, false)
+                }
+                AluOp::Shl => {
+                    ret.shl(b);
+                    (false, false)
+                }
+                AluOp::Shr => {
+                    ret.shr(b);
+                    (false, false)
+                }
+                AluOp::Sar => {
+                    ret.sar(b);
+                    (false, false)
+                }
+                AluOp::Rol => {
+                    ret.rol(b);
+                    (false, false)
+                }
+                AluOp::Ror => {
+                    ret.ror(b);
+                    (false, false)
+                }
+            };
+            self.flags_reg.bit(CARRY_BIT_NUM).store(carry, SeqCst);
+            self.flags_reg.bit(OVERFLOW_BIT_NUM).store(overflow, SeqCst);
+            self.flags_reg.bit(ZERO_BIT_NUM).store(ret.as_u32() == 0, SeqCst);
+            self.flags_reg.bit(SIGN_BIT_NUM).store(ret.as_u32() & SIGN_MASK != 0, SeqCst);
             bus.write_from(&ret);
         }
     }
@@ -216,56 +414,171 @@ impl CpuComponent for AluComponent {
     fn step_print(&self) {
         println!("alu: a {} b {}", self.reg_a.as_u32(), self.reg_b.as_u32());
     }
+
+    fn writes_bus(&self, cables: &ControlCables) -> bool {
+        cables.load(AluOut)
+    }
+
+    /// a and b arrive through the channel from the registers
+    fn reads_bus(&self, _cables: &ControlCables) -> bool {
+        false
+    }
 }
 
-pub struct ControlComponent<'a> {
-    pub clock_rx: Receiver<()>,
-    pub txs: Vec<Sender<()>>,
-    pub finished: &'a AtomicUsize,
-    pub alu_clock_rx: Receiver<()>,
-    pub sent_to_alu: Arc<AtomicUsize>,
-    pub cables: &'a ControlCables,
+/// How the control component drives the other components through a cycle.
+pub enum Clock {
+    /// Every component and the alu run in their own threads.
+    Threaded {
+        clock_rx: Receiver<()>,
+        txs: Vec<Sender<()>>,
+        finished: Arc<AtomicUsize>,
+        alu_clock_rx: Receiver<()>,
+        sent_to_alu: Arc<AtomicUsize>,
+        ctrl_rx: Receiver<MValue>,
+    },
+    /// The components are stepped one after another on the clock thread, the
+    /// bus writer first so that the readers never wait.
+    Sequential {
+        alu: Arc<AluComponent>,
+        alu_rx: Receiver<(usize, MValue)>,
+        sent_to_alu: Arc<AtomicUsize>,
+    },
+}
+
+pub struct ControlComponent {
+    pub clock: Clock,
+    /// every component on the bus, in the order the sequential clock steps them
+    pub components: Vec<Arc<dyn CpuComponent + Send + Sync>>,
+    pub cables: Arc<ControlCables>,
     pub bus: Arc<Bus>,
     pub microcode_counter: AtomicUsize,
     pub current_microcodes: Arc<Mutex<Microcodes>>,
-    pub instruction_register: MValue,
-    pub clock_step_rx: Receiver<()>,
-    pub clock_step: bool,
-    pub flags_register: Arc<MValue>,
+    /// the words loaded into the instruction register, shifted in one by one
+    pub instruction_register: AtomicU32,
+    pub flags_register: Arc<Flags>,
+    pub interrupt_controller: Arc<InterruptController>,
+    pub ports: Arc<PortMap>,
+    /// how long a cycle may take before it is reported as stuck, cycles
+    /// reading a port can wait on input and are never timed out
+    pub cycle_timeout: Option<Duration>,
 }
 
-impl<'a> ControlComponent<'a> {
-    pub fn run(&self, ctrl_rx: Receiver<MValue>) {
-        loop {
-            if self.clock_step {
-                self.clock_step_rx.recv().unwrap();
-                self.step_print();
-            }
-            self.set_cables(self.cables);
-            if self.cables.load(Halt) {
-                println!("\nclock: halt");
-                break;
-            }
-            for t in &self.txs {
-                t.send(()).unwrap();
-            }
-            loop {
-                self.clock_rx.recv().unwrap();
-                let amount_finished = self.finished.load(SeqCst);
-                if amount_finished == self.txs.len() {
-                    self.finished.store(0, SeqCst);
-                    break;
+/// Waits for a component, or fails once the cycle timeout has passed.
+fn recv_within<T>(rx: &Receiver<T>, timeout: Option<Duration>) -> Result<T, BusError> {
+    match timeout {
+        Some(t) => rx.recv_timeout(t).map_err(|e| match e {
+            RecvTimeoutError::Timeout => BusError::Timeout,
+            RecvTimeoutError::Disconnected => BusError::Disconnected,
+        }),
+        None => rx.recv().map_err(|_| BusError::Disconnected),
+    }
+}
+
+impl ControlComponent {
+    /// Runs a single clock cycle, returns true if the cpu has halted. Cycles
+    /// that can't drive the bus fail before any component steps.
+    pub fn tick(&self) -> Result<bool, BusError> {
+        self.set_cables(&self.cables);
+        if self.cables.load(Halt) {
+            return Ok(true);
+        }
+        let writer = bus_writer(&self.components, &self.cables)?;
+        let timeout = if self.cables.load(RamOut) && self.cables.load(MemoryIsIO) {
+            None
+        } else {
+            self.cycle_timeout
+        };
+        self.bus.start_cycle(timeout);
+        match &self.clock {
+            Clock::Threaded {
+                clock_rx,
+                txs,
+                finished,
+                alu_clock_rx,
+                sent_to_alu,
+                ctrl_rx,
+            } => {
+                for t in txs {
+                    t.send(()).map_err(|_| BusError::Disconnected)?;
+                }
+                loop {
+                    recv_within(clock_rx, timeout)?;
+                    let amount_finished = finished.load(SeqCst);
+                    if amount_finished == txs.len() {
+                        finished.store(0, SeqCst);
+                        break;
+                    }
+                }
+                for _ in 0..sent_to_alu.load(SeqCst) {
+                    recv_within(alu_clock_rx, timeout)?;
+                }
+                sent_to_alu.store(0, SeqCst);
+                if let Ok(mvalue) = ctrl_rx.try_recv() {
+                    self.latch_instruction_word(mvalue.as_u32());
                 }
             }
-            for _ in 0..self.sent_to_alu.load(SeqCst) {
-                self.alu_clock_rx.recv().unwrap();
-            }
-            self.sent_to_alu.store(0, SeqCst);
-            match ctrl_rx.try_recv() {
-                Ok(mvalue) => self.instruction_register.set(&mvalue),
-                Err(_) => {}
+            Clock::Sequential {
+                alu,
+                alu_rx,
+                sent_to_alu,
+            } => {
+                if let Some(w) = writer {
+                    self.components[w].step(self.bus.clone(), &self.cables);
+                }
+                for (i, c) in self.components.iter().enumerate() {
+                    if Some(i) != writer {
+                        c.step(self.bus.clone(), &self.cables);
+                    }
+                }
+                for (reg_num, mvalue) in alu_rx.try_iter() {
+                    alu.latch(reg_num, &mvalue);
+                    if reg_num == INSTRUCTION_REG_NUM {
+                        self.latch_instruction_word(mvalue.as_u32());
+                    }
+                }
+                sent_to_alu.store(0, SeqCst);
             }
         }
+        if self.bus.timed_out() {
+            return Err(BusError::Timeout);
+        }
+        self.ports.tick(&self.interrupt_controller);
+        Ok(false)
+    }
+
+    /// The instruction, microcode step and asserted cables of the last cycle.
+    pub fn report(&self) -> String {
+        format!(
+            "instruction: {}, microcode step: {}, cables: {}",
+            decode::decode_instruction(self.instruction_register.load(SeqCst)),
+            self.microcode_counter.load(SeqCst).saturating_sub(1),
+            decode::dump_cables(&self.cables).trim_end(),
+        )
+    }
+
+    /// Appends a word loaded into the instruction register to the instruction.
+    pub fn latch_instruction_word(&self, word: u32) {
+        let instruction = self.instruction_register.load(SeqCst);
+        self.instruction_register.store(shift_in_word(instruction, word), SeqCst);
+    }
+
+    /// Whether the next cycle starts a new instruction.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.microcode_counter.load(SeqCst) == self.current_microcodes.lock().len()
+    }
+
+    /// Whether the next instruction is replaced by serving an irq.
+    fn serves_irq(&self) -> bool {
+        self.flags_register.bit(INTERRUPT_ENABLE_BIT_NUM).load(SeqCst)
+            && self.interrupt_controller.active_line().is_some()
+    }
+
+    /// The exception the next cycle raises, if it starts a new instruction.
+    pub fn next_exception(&self) -> Option<Exception> {
+        if !self.at_instruction_boundary() || self.serves_irq() {
+            return None;
+        }
+        instruction_exception(self.instruction_register.load(SeqCst), &self.flags_register)
     }
 
     fn set_cables(&self, cables: &ControlCables) {
@@ -273,7 +586,12 @@ impl<'a> ControlComponent<'a> {
         let mut current_microcodes = self.current_microcodes.lock();
 
         if self.microcode_counter.load(SeqCst) == current_microcodes.len() {
-            *current_microcodes = create_microcodes(self.instruction_register.as_u32(), &*self.flags_register);
+            if self.serves_irq() {
+                *current_microcodes = create_irq_microcodes();
+            } else {
+                let instruction = self.instruction_register.load(SeqCst);
+                *current_microcodes = create_microcodes(instruction, &self.flags_register);
+            }
             self.microcode_counter.store(0, SeqCst);
         }
 
@@ -287,60 +605,109 @@ impl<'a> ControlComponent<'a> {
     pub fn step_print(&self) {
         println!(
             "control: ir: {} microcode_counter: {} cables: {}",
-            decode::decode_instruction(self.instruction_register.as_u32()),
+            decode::decode_instruction(self.instruction_register.load(SeqCst)),
             self.microcode_counter.load(SeqCst),
-            decode::dump_cables(self.cables),
+            decode::dump_cables(&self.cables),
         );
     }
 }
 
+/// The memory accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn covers(&self, access: Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addresses: Range<u32>,
+    pub access: Access,
+}
+
+/// An access of the ram caught by a watchpoint, the value read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u32,
+    pub access: Access,
+    pub value: u32,
+}
+
 pub struct RamComponent {
     pub memory: Box<[MValue; RAM_SIZE]>,
     pub memory_address_register: MValue,
     pub ram_register: MValue,
-    pub output_tx: Arc<Mutex<Sender<(MValue, MValue)>>>,
-    pub input_req_tx: Arc<Mutex<Sender<MValue>>>,
-    pub input_rx: Arc<Mutex<Receiver<Option<MValue>>>>,
+    pub ports: Arc<PortMap>,
+    pub watchpoints: Mutex<Vec<Watchpoint>>,
+    /// hits since the last `take_watch_hits`
+    pub watch_hits: Mutex<Vec<WatchHit>>,
+}
+
+impl RamComponent {
+    fn watch(&self, address: u32, access: Access) {
+        let watched = self
+            .watchpoints
+            .lock()
+            .iter()
+            .any(|w| w.access.covers(access) && w.addresses.contains(&address));
+        if watched {
+            self.watch_hits.lock().push(WatchHit {
+                address,
+                access,
+                value: self.ram_register.as_u32(),
+            });
+        }
+    }
+
+    pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut *self.watch_hits.lock())
+    }
 }
 
 impl CpuComponent for RamComponent {
     fn step(&self, bus: Arc<Bus>, cables: &ControlCables) {
         if cables.load(MemoryAddressIn) {
             bus.read_into(&self.memory_address_register);
+            if cables.load(Interrupt) {
+                // the bus carries an interrupt number, address its vector instead
+                let vector = self.memory_address_register.as_u32() as usize % INTERRUPT_VECTORS_NUM;
+                self.memory_address_register.set_u32((INTERRUPT_VECTOR_TABLE + vector) as u32);
+            }
             if !cables.load(MemoryIsIO) {
-                let memory_index = self.memory_address_register.as_u32() as usize;
+                let memory_index = self.memory_address_register.as_u32() as usize % RAM_SIZE;
                 self.ram_register.set(&self.memory[memory_index]);
             }
         }
         if cables.load(RamIn) {
             bus.read_into(&self.ram_register);
             if cables.load(MemoryIsIO) {
-                self.output_tx
-                    .lock()
-                    .send((
-                        self.memory_address_register.clone(),
-                        self.ram_register.clone(),
-                    ))
-                    .unwrap();
+                self.ports.write(
+                    self.memory_address_register.as_u32(),
+                    self.ram_register.as_u32(),
+                );
             } else {
-                let memory_index = self.memory_address_register.as_u32() as usize;
+                let memory_index = self.memory_address_register.as_u32() as usize % RAM_SIZE;
                 self.memory[memory_index].set(&self.ram_register);
+                self.watch(memory_index as u32, Access::Write);
             }
         }
         if cables.load(RamOut) {
             if cables.load(MemoryIsIO) {
-                self.input_req_tx
-                    .lock()
-                    .send(self.memory_address_register.clone())
-                    .unwrap();
-                let v = self.input_rx.lock().recv().unwrap();
-                if v.is_some() {
-                    bus.write_from(&v.unwrap());
-                }
+                let port = self.memory_address_register.as_u32();
+                self.ram_register.set_u32(self.ports.read(port));
+                bus.write_from(&self.ram_register);
             } else {
-                let memory_index = self.memory_address_register.as_u32() as usize;
+                let memory_index = self.memory_address_register.as_u32() as usize % RAM_SIZE;
                 self.ram_register.set(&self.memory[memory_index]);
                 bus.write_from(&self.ram_register);
+                self.watch(memory_index as u32, Access::Read);
             }
         }
     }
@@ -352,4 +719,12 @@ impl CpuComponent for RamComponent {
             self.ram_register.as_u32()
         );
     }
+
+    fn writes_bus(&self, cables: &ControlCables) -> bool {
+        cables.load(RamOut)
+    }
+
+    fn reads_bus(&self, cables: &ControlCables) -> bool {
+        cables.load(MemoryAddressIn) || cables.load(RamIn)
+    }
 }
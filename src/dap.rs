@@ -0,0 +1,524 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::bits::WORD_BYTES;
+use crate::cpu_component::{
+    PROGRAM_COUNTER_REG_NUM, RAM_SIZE, REGISTERS_NUM, STACK_POINTER_REG_NUM,
+};
+use crate::debugger::{parse_number, Debugger, Symbols};
+use crate::decode::{register_name, register_number};
+use crate::machine::{Machine, MachineStatus};
+use crate::microcodes::INSTRUCTION::{CALL, POP};
+use crate::microcodes::{DEST_MASK, DEST_SHIFT, OPCODE_MASK, OPCODE_SHIFT};
+
+/// the only thread, the cpu
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+/// size of the byte addressed memory of readMemory and writeMemory
+const RAM_BYTES: u64 = (RAM_SIZE * WORD_BYTES) as u64;
+/// instructions run between checks for a pause request
+const PAUSE_CHECK_INSTRUCTIONS: u32 = 1000;
+
+/// How far a resumed machine runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    /// to the next line or instruction, running calls until they return
+    Over,
+    /// to the next line or instruction
+    Into,
+    /// until the current call returns
+    Out,
+}
+
+/// Messages of the debug adapter protocol, each a json object after a
+/// Content-Length header.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    seq: u64,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> std::io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            seq: 0,
+        })
+    }
+
+    /// The next message, None at the end of the connection.
+    fn read(&mut self) -> std::io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let length = length.ok_or(std::io::Error::from(ErrorKind::InvalidData))?;
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Whether a message arrived, without waiting for one.
+    fn pending(&mut self) -> std::io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        let stream = self.reader.get_ref();
+        stream.set_nonblocking(true)?;
+        let ret = match stream.peek(&mut [0]) {
+            Ok(n) => n > 0,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(ret)
+    }
+
+    fn send(&mut self, mut message: Value) -> std::io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.writer.flush()
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> std::io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> std::io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> std::io::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+}
+
+fn parse_reference(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => parse_number(s).map(u64::from),
+        value => value.as_u64(),
+    }
+}
+
+/// A debug adapter protocol server letting editors debug a program at the
+/// level of its assembly source, mapped through the symbols file.
+///
+/// The memory view is byte addressed, word n of the memory is at bytes
+/// n * WORD_BYTES onwards, big endian.
+pub struct DapServer {
+    debugger: Debugger,
+    symbols: Symbols,
+    stop_on_entry: bool,
+}
+
+impl DapServer {
+    pub fn new(machine: Machine, symbols: Symbols) -> Self {
+        let debugger = Debugger::new(machine).with_symbols(symbols.labels.clone());
+        DapServer {
+            debugger,
+            symbols,
+            stop_on_entry: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        self.debugger.machine()
+    }
+
+    /// Waits for an editor to connect to address and serves it until it
+    /// disconnects.
+    pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> std::io::Result<()> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        self.serve(stream)
+    }
+
+    pub fn serve(&mut self, stream: TcpStream) -> std::io::Result<()> {
+        let mut connection = Connection::new(stream)?;
+        while let Some(request) = connection.read()? {
+            if !self.handle(&request, &mut connection)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn running(&self) -> bool {
+        self.machine().status() == MachineStatus::Running
+    }
+
+    fn current_line(&self) -> Option<u32> {
+        self.symbols.line_of(self.machine().next_address())
+    }
+
+    /// Whether the instruction about to run is a return, a pop into pc.
+    fn at_return(&self) -> bool {
+        let instruction = self.machine().instruction();
+        (instruction & OPCODE_MASK) >> OPCODE_SHIFT == POP as u32
+            && (instruction & DEST_MASK) >> DEST_SHIFT == PROGRAM_COUNTER_REG_NUM as u32
+    }
+
+    /// Whether the instruction about to run is a call.
+    fn at_call(&self) -> bool {
+        let instruction = self.machine().instruction();
+        (instruction & OPCODE_MASK) >> OPCODE_SHIFT == CALL as u32
+            && self.machine().at_instruction_boundary()
+    }
+
+    /// Answers a request, returns false once the session has ended.
+    fn handle(&mut self, request: &Value, connection: &mut Connection) -> std::io::Result<bool> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                connection.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsSetVariable": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsWriteMemoryRequest": true,
+                        "supportsSteppingGranularity": true,
+                    }),
+                )?;
+                connection.event("initialized", json!({}))?;
+            }
+            "launch" | "attach" => {
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                connection.respond(request, json!({}))?;
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args);
+                connection.respond(request, body)?;
+            }
+            "setExceptionBreakpoints" => connection.respond(request, json!({}))?,
+            "configurationDone" => {
+                connection.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.send_stop("entry", connection)?;
+                } else {
+                    return self.resume(Resume::Continue, false, connection);
+                }
+            }
+            "threads" => {
+                connection.respond(request, json!({"threads": [{"id": THREAD_ID, "name": "cpu"}]}))?
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                connection.respond(request, body)?;
+            }
+            "scopes" => connection.respond(
+                request,
+                json!({"scopes": [{
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REFERENCE,
+                    "expensive": false,
+                }]}),
+            )?,
+            "variables" => {
+                let variables = match args["variablesReference"].as_u64() {
+                    Some(REGISTERS_REFERENCE) => self.registers(),
+                    _ => vec![],
+                };
+                connection.respond(request, json!({ "variables": variables }))?;
+            }
+            "setVariable" => match self.set_variable(args) {
+                Ok(body) => connection.respond(request, body)?,
+                Err(e) => connection.respond_error(request, &e)?,
+            },
+            "readMemory" => match self.read_memory(args) {
+                Some(body) => connection.respond(request, body)?,
+                None => connection.respond_error(request, "wrong memory reference")?,
+            },
+            "writeMemory" => match self.write_memory(args) {
+                Some(body) => connection.respond(request, body)?,
+                None => connection.respond_error(request, "wrong memory reference or data")?,
+            },
+            command @ ("continue" | "next" | "stepIn" | "stepOut") => {
+                let resume = match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Over,
+                    "stepIn" => Resume::Into,
+                    _ => Resume::Out,
+                };
+                let by_instruction = args["granularity"].as_str() == Some("instruction");
+                connection.respond(request, json!({"allThreadsContinued": true}))?;
+                return self.resume(resume, by_instruction, connection);
+            }
+            "pause" => connection.respond(request, json!({}))?,
+            "disconnect" => {
+                connection.respond(request, json!({}))?;
+                return Ok(false);
+            }
+            "terminate" => {
+                connection.respond(request, json!({}))?;
+                connection.event("terminated", json!({}))?;
+                return Ok(false);
+            }
+            command => {
+                connection.respond_error(request, &format!("unsupported request: {}", command))?
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs the machine and tells the editor where it stopped. Requests that
+    /// arrive meanwhile are answered, a pause stops the machine.
+    fn resume(
+        &mut self,
+        resume: Resume,
+        by_instruction: bool,
+        connection: &mut Connection,
+    ) -> std::io::Result<bool> {
+        let start_line = self.current_line();
+        let start_sp = self.machine().register(STACK_POINTER_REG_NUM);
+        let mut instructions: u32 = 0;
+        let mut reason = "step";
+        // the return address and stack pointer of the call being stepped over,
+        // it runs one instruction at a time so a pause can stop it
+        let mut over_call = None;
+        while self.running() {
+            let returning = self.at_return();
+            if resume == Resume::Over && over_call.is_none() && self.at_call() {
+                over_call = Some((
+                    self.machine().register(PROGRAM_COUNTER_REG_NUM),
+                    self.machine().register(STACK_POINTER_REG_NUM),
+                ));
+            }
+            self.debugger.step_instruction(false);
+            if !self.running() {
+                break;
+            }
+            if self.debugger.at_breakpoint() {
+                reason = "breakpoint";
+                break;
+            }
+            if over_call
+                == Some((
+                    self.debugger.next_address(),
+                    self.machine().register(STACK_POINTER_REG_NUM),
+                ))
+            {
+                over_call = None;
+            }
+            let done = match resume {
+                Resume::Continue => false,
+                Resume::Over => {
+                    over_call.is_none() && (by_instruction || self.current_line() != start_line)
+                }
+                Resume::Into => by_instruction || self.current_line() != start_line,
+                // nested calls return to a stack pointer at or below the start
+                Resume::Out => {
+                    returning && self.machine().register(STACK_POINTER_REG_NUM) > start_sp
+                }
+            };
+            if done {
+                break;
+            }
+            instructions += 1;
+            if instructions.is_multiple_of(PAUSE_CHECK_INSTRUCTIONS) && connection.pending()? {
+                let Some(request) = connection.read()? else {
+                    return Ok(false);
+                };
+                match request["command"].as_str().unwrap_or_default() {
+                    "pause" => {
+                        connection.respond(&request, json!({}))?;
+                        reason = "pause";
+                        break;
+                    }
+                    "continue" | "next" | "stepIn" | "stepOut" => {
+                        connection.respond(&request, json!({"allThreadsContinued": true}))?
+                    }
+                    _ => {
+                        if !self.handle(&request, connection)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+        }
+        self.send_stop(reason, connection)?;
+        Ok(true)
+    }
+
+    fn send_stop(&mut self, reason: &str, connection: &mut Connection) -> std::io::Result<()> {
+        let exception = |description: String, text: Option<&str>| {
+            json!({
+                "reason": "exception",
+                "description": description,
+                "text": text,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            })
+        };
+        match self.machine().status() {
+            MachineStatus::Running => connection.event(
+                "stopped",
+                json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}),
+            ),
+            MachineStatus::Halted => {
+                connection.event("exited", json!({"exitCode": self.machine().exit_code()}))?;
+                connection.event("terminated", json!({}))
+            }
+            MachineStatus::Faulted(e) => {
+                connection.event("stopped", exception(format!("unhandled exception: {}", e), None))
+            }
+            MachineStatus::BusError(e) => connection.event(
+                "stopped",
+                exception(format!("bus error: {}", e), self.machine().report()),
+            ),
+        }
+    }
+
+    /// Replaces the breakpoints, moving each to the first line with an
+    /// instruction at or after it.
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        self.debugger.breakpoints_mut().clear();
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let breakpoints: Vec<Value> = requested
+            .iter()
+            .map(|b| {
+                let line = b["line"].as_u64().unwrap_or_default() as u32;
+                match self.symbols.address_of(line) {
+                    Some((address, line)) => {
+                        self.debugger.breakpoints_mut().insert(address);
+                        json!({"verified": true, "line": line})
+                    }
+                    None => json!({"verified": false, "line": line, "message": "no instruction here"}),
+                }
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let address = self.machine().next_address();
+        let name = match self.symbols.label_before(address) {
+            Some(label) => label.to_string(),
+            None => format!("{:#x}", address),
+        };
+        let mut frame = json!({
+            "id": 0,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("{:#x}", address),
+        });
+        if let (Some(line), Some(source)) = (self.current_line(), &self.symbols.source) {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": source.file_name().map(|n| n.to_string_lossy()),
+                "path": source,
+            });
+        }
+        json!({"stackFrames": [frame], "totalFrames": 1})
+    }
+
+    fn registers(&self) -> Vec<Value> {
+        let machine = self.machine();
+        let mut ret: Vec<Value> = (0..REGISTERS_NUM)
+            .map(|r| {
+                // pc is shown as the address of the instruction about to run
+                let value = match r {
+                    PROGRAM_COUNTER_REG_NUM => machine.next_address(),
+                    r => machine.register(r),
+                };
+                json!({
+                    "name": register_name(r).unwrap(),
+                    "value": format!("{} ({:#x})", value, value),
+                    "variablesReference": 0,
+                    "memoryReference": format!("{:#x}", value as usize * WORD_BYTES),
+                })
+            })
+            .collect();
+        ret.push(json!({
+            "name": "flags",
+            "value": format!("{:#b}", machine.flags()),
+            "variablesReference": 0,
+        }));
+        ret
+    }
+
+    fn set_variable(&self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or_default();
+        let text = args["value"].as_str().unwrap_or_default();
+        let value = parse_number(text.trim()).ok_or(format!("not a number: {}", text))?;
+        let machine = self.machine();
+        match register_number(name) {
+            Some(PROGRAM_COUNTER_REG_NUM) => machine.jump(value),
+            Some(reg_num) => machine.set_register(reg_num, value),
+            None if name == "flags" => machine.set_flags(value),
+            None => return Err(format!("unknown variable: {}", name)),
+        }
+        Ok(json!({ "value": format!("{} ({:#x})", value, value) }))
+    }
+
+    fn read_byte(&self, address: u64) -> u8 {
+        let word = self.machine().read_ram((address / WORD_BYTES as u64) as u32);
+        word.to_be_bytes()[4 - WORD_BYTES + (address % WORD_BYTES as u64) as usize]
+    }
+
+    fn write_byte(&self, address: u64, byte: u8) {
+        let word_address = (address / WORD_BYTES as u64) as u32;
+        let mut bytes = self.machine().read_ram(word_address).to_be_bytes();
+        bytes[4 - WORD_BYTES + (address % WORD_BYTES as u64) as usize] = byte;
+        self.machine().write_ram(word_address, u32::from_be_bytes(bytes));
+    }
+
+    fn read_memory(&self, args: &Value) -> Option<Value> {
+        let start = parse_reference(&args["memoryReference"])?
+            .checked_add_signed(args["offset"].as_i64().unwrap_or_default())?;
+        let count = args["count"].as_u64()?;
+        // bytes past the end of the ram are reported as unreadable
+        let end = start.saturating_add(count).min(RAM_BYTES);
+        let data: Vec<u8> = (start.min(end)..end).map(|a| self.read_byte(a)).collect();
+        let mut ret = json!({"address": format!("{:#x}", start), "data": BASE64.encode(&data)});
+        if count > data.len() as u64 {
+            ret["unreadableBytes"] = json!(count - data.len() as u64);
+        }
+        Some(ret)
+    }
+
+    fn write_memory(&self, args: &Value) -> Option<Value> {
+        let start = parse_reference(&args["memoryReference"])?
+            .checked_add_signed(args["offset"].as_i64().unwrap_or_default())?;
+        let data = BASE64.decode(args["data"].as_str()?).ok()?;
+        let end = start.saturating_add(data.len() as u64).min(RAM_BYTES);
+        for (address, byte) in (start.min(end)..end).zip(&data) {
+            self.write_byte(address, *byte);
+        }
+        Some(json!({ "bytesWritten": end - start.min(end) }))
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu_component::{
+    Access, Watchpoint, PROGRAM_COUNTER_REG_NUM, REGISTERS_NUM, STACK_POINTER_REG_NUM,
+};
+use crate::decode::{
+    cable_name, cable_number, decode_instruction, register_name, register_number,
+};
+use crate::machine::{Machine, MachineStatus};
+use crate::snapshot::Snapshot;
+use crate::microcodes::INSTRUCTION::{CALL, LDCNST};
+use crate::microcodes::{INSTRUCTION_WORDS, OPCODE_MASK, OPCODE_SHIFT};
+
+/// instructions disassembled before and after the location
+const DISASSEMBLE_AROUND: u32 = 4;
+/// words examined without a count
+const EXAMINE_WORDS: u32 = 8;
+/// the most words one x command prints
+const EXAMINE_MAX_WORDS: u32 = 1024;
+
+const HELP: &str = "\
+step [n]           run n instructions
+next [n]           run n instructions, running calls until they return
+micro [n]          run n microcode steps and show their cables
+continue           run until a breakpoint or the end
+break [location]   break before the instruction at location, or list everything
+                   that stops the machine
+watch location [n] stop on writes of n words of memory
+rwatch location [n] stop on reads of n words of memory
+awatch location [n] stop on reads and writes of n words of memory
+cbreak cable       stop on cycles asserting a cable, named like in micro
+cond target op value  stop when a register or the flags start to compare to
+                   value, op is one of == != < <= > >=
+delete [location]  delete the breakpoint at location, the stop numbered #n, or
+                   everything
+regs               print the registers and flags
+x location [n]     examine n words of memory, at most 1024
+set target value   set a register, or the word at an address
+disas [location]   disassemble around location, the next instruction by default
+components         print the state of every component
+snapshot file      save the state of the machine
+restore file       go back to a saved state
+quit
+locations are addresses, 0x prefixed hex addresses or labels of the symbols
+file, an empty line repeats the last command";
+
+/// What `asm --symbols` knows about a binary.
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// the assembled source file
+    pub source: Option<PathBuf>,
+    pub labels: HashMap<String, u32>,
+    /// the source line of every instruction, by address
+    pub lines: BTreeMap<u32, u32>,
+}
+
+impl Symbols {
+    /// The line of the instruction at address, or of the closest one before it.
+    pub fn line_of(&self, address: u32) -> Option<u32> {
+        self.lines.range(..=address).next_back().map(|(_, line)| *line)
+    }
+
+    /// The first instruction of line, or of the closest line after it with one.
+    /// Returns the address and its line.
+    pub fn address_of(&self, line: u32) -> Option<(u32, u32)> {
+        self.lines
+            .iter()
+            .filter(|(_, l)| **l >= line)
+            .min_by_key(|(address, l)| (**l, **address))
+            .map(|(address, l)| (*address, *l))
+    }
+
+    /// The closest label at or before address.
+    pub fn label_before(&self, address: u32) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, a)| **a <= address)
+            .max_by_key(|(name, a)| (**a, std::cmp::Reverse(name.as_str())))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Reads the file written by `asm --symbols`, made of `source path`,
+/// `label name address` and `line line address` lines.
+pub fn read_symbols<P: AsRef<Path>>(path: P) -> std::io::Result<Symbols> {
+    let mut ret = Symbols::default();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let Some((kind, rest)) = line.split_once(' ') else {
+            continue;
+        };
+        if kind == "source" {
+            ret.source = Some(PathBuf::from(rest));
+            continue;
+        }
+        let Some((key, address)) = rest.split_once(' ') else {
+            continue;
+        };
+        let Ok(address) = address.trim().parse() else {
+            continue;
+        };
+        match kind {
+            "label" => {
+                ret.labels.insert(key.to_string(), address);
+            }
+            "line" => {
+                if let Ok(line) = key.parse() {
+                    ret.lines.insert(address, line);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+pub(crate) fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// How a register condition compares the register to its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    const SYMBOLS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<", Comparison::Less),
+        ("<=", Comparison::LessOrEqual),
+        (">", Comparison::Greater),
+        (">=", Comparison::GreaterOrEqual),
+    ];
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::SYMBOLS.iter().find(|(symbol, _)| *symbol == s).map(|(_, c)| *c)
+    }
+
+    fn symbol(&self) -> &'static str {
+        Self::SYMBOLS.iter().find(|(_, c)| c == self).unwrap().0
+    }
+
+    fn holds(&self, a: u32, b: u32) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+/// Stops the machine in the middle of an instruction, checked after every
+/// cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Watch(Watchpoint),
+    /// a cable, numbered like in `ControlCables`
+    Cable(usize),
+    /// a register, or the flags as REGISTERS_NUM, compared to a value. Stops
+    /// when the comparison starts to hold.
+    Condition(usize, Comparison, u32),
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stop::Watch(w) => {
+                let command = match w.access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                    Access::ReadWrite => "awatch",
+                };
+                let words = w.addresses.end - w.addresses.start;
+                write!(f, "{} {} {}", command, w.addresses.start, words)
+            }
+            Stop::Cable(cable) => write!(f, "cbreak {}", cable_name(*cable)),
+            Stop::Condition(target, comparison, value) => {
+                write!(f, "cond {} {} {}", target_name(*target), comparison.symbol(), value)
+            }
+        }
+    }
+}
+
+fn target_name(target: usize) -> &'static str {
+    register_name(target).unwrap_or("flags")
+}
+
+/// A command prompt driving a machine one instruction at a time.
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<u32>,
+    stops: Vec<Stop>,
+    /// whether each condition of `stops` held after the last cycle
+    conditions_held: Vec<bool>,
+    /// what stopped the machine in the middle of an instruction
+    hit: Option<String>,
+    symbols: HashMap<String, u32>,
+}
+
+impl Debugger {
+    /// Runs the first fetch, or the rest of a restored instruction, stopping
+    /// before the next instruction.
+    pub fn new(mut machine: Machine) -> Self {
+        if !machine.at_instruction_boundary() {
+            machine.step_instruction();
+        }
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+            stops: Vec::new(),
+            conditions_held: Vec::new(),
+            hit: None,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn with_symbols(mut self, symbols: HashMap<String, u32>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<u32> {
+        &mut self.breakpoints
+    }
+
+    pub fn stops(&self) -> &[Stop] {
+        &self.stops
+    }
+
+    pub fn add_stop(&mut self, stop: Stop) {
+        self.conditions_held.push(self.condition_holds(&stop));
+        self.stops.push(stop);
+        self.update_watchpoints();
+    }
+
+    pub fn remove_stop(&mut self, i: usize) -> Option<Stop> {
+        if i >= self.stops.len() {
+            return None;
+        }
+        self.conditions_held.remove(i);
+        let ret = self.stops.remove(i);
+        self.update_watchpoints();
+        Some(ret)
+    }
+
+    fn update_watchpoints(&self) {
+        let watchpoints = self.stops.iter().filter_map(|stop| match stop {
+            Stop::Watch(w) => Some(w.clone()),
+            _ => None,
+        });
+        self.machine.set_watchpoints(watchpoints.collect());
+    }
+
+    fn condition_holds(&self, stop: &Stop) -> bool {
+        match stop {
+            Stop::Condition(target, comparison, value) => {
+                let current = match *target {
+                    REGISTERS_NUM => self.machine.flags(),
+                    r => self.machine.register(r),
+                };
+                comparison.holds(current, *value)
+            }
+            _ => false,
+        }
+    }
+
+    /// What stopped the machine in the middle of an instruction.
+    pub fn hit(&self) -> Option<&str> {
+        self.hit.as_deref()
+    }
+
+    /// The address of the instruction about to run.
+    pub fn next_address(&self) -> u32 {
+        self.machine.next_address()
+    }
+
+    fn running(&self) -> bool {
+        self.machine.status() == MachineStatus::Running
+    }
+
+    fn location(&self, s: &str) -> Result<u32, String> {
+        match self.symbols.get(s) {
+            Some(address) => Ok(*address),
+            None => parse_number(s).ok_or(format!("unknown location: {}", s)),
+        }
+    }
+
+    fn label_at(&self, address: u32) -> Option<&str> {
+        self.symbols
+            .iter()
+            .filter(|(_, a)| **a == address)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    fn describe(&self, address: u32) -> String {
+        let instruction = decode_instruction(self.machine.read_instruction(address));
+        match self.label_at(address) {
+            Some(label) => format!("{} <{}>: {}", address, label, instruction),
+            None => format!("{}: {}", address, instruction),
+        }
+    }
+
+    /// Where the machine stopped.
+    pub fn stop_message(&self) -> String {
+        match self.machine.status() {
+            MachineStatus::Running => {
+                let place = if self.machine.at_instruction_boundary() {
+                    self.describe(self.next_address())
+                } else {
+                    format!(
+                        "in {} at microcode step {}",
+                        decode_instruction(self.machine.instruction()),
+                        self.machine.microcode_counter().saturating_sub(1)
+                    )
+                };
+                match &self.hit {
+                    Some(hit) => format!("{}\n{}", hit, place),
+                    None => place,
+                }
+            }
+            MachineStatus::Halted => "halted".to_string(),
+            MachineStatus::Faulted(e) => {
+                format!("unhandled exception: {} at {}", e, self.next_address())
+            }
+            MachineStatus::BusError(e) => {
+                format!("bus error: {}\n{}", e, self.machine.report().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Whether the instruction about to run has a breakpoint.
+    pub fn at_breakpoint(&self) -> bool {
+        self.running() && self.breakpoints.contains(&self.next_address())
+    }
+
+    fn stopped(&self) -> bool {
+        self.hit.is_some() || self.at_breakpoint()
+    }
+
+    /// Runs one clock cycle and records the stops it hits.
+    fn step_cycle(&mut self) {
+        if self.machine.step_cycle() != MachineStatus::Running {
+            return;
+        }
+        let mut hits = Vec::new();
+        for hit in self.machine.take_watch_hits() {
+            let watched = self.stops.iter().position(|stop| match stop {
+                Stop::Watch(w) => w.access.covers(hit.access) && w.addresses.contains(&hit.address),
+                _ => false,
+            });
+            let Some(i) = watched else {
+                continue;
+            };
+            let access = if hit.access == Access::Read { "read" } else { "write" };
+            hits.push(format!(
+                "#{} {}: {} of {} at {}",
+                i + 1,
+                self.stops[i],
+                access,
+                hit.value,
+                hit.address
+            ));
+        }
+        for i in 0..self.stops.len() {
+            match self.stops[i] {
+                Stop::Cable(cable) if self.machine.cable_asserted(cable) => {
+                    hits.push(format!("#{} {}", i + 1, self.stops[i]));
+                }
+                Stop::Condition(target, _, _) => {
+                    let held = self.condition_holds(&self.stops[i]);
+                    if held && !self.conditions_held[i] {
+                        let current = match target {
+                            REGISTERS_NUM => self.machine.flags(),
+                            r => self.machine.register(r),
+                        };
+                        hits.push(format!(
+                            "#{} {}: {} is {}",
+                            i + 1,
+                            self.stops[i],
+                            target_name(target),
+                            current
+                        ));
+                    }
+                    self.conditions_held[i] = held;
+                }
+                _ => {}
+            }
+        }
+        if !hits.is_empty() {
+            self.hit = Some(hits.join("\n"));
+        }
+    }
+
+    /// Runs the cycles left of the instruction, stopping early at a hit.
+    fn run_instruction(&mut self) {
+        loop {
+            self.step_cycle();
+            if !self.running() || self.hit.is_some() || self.machine.at_instruction_boundary() {
+                break;
+            }
+        }
+    }
+
+    /// Runs a call until it returns to the instruction after it.
+    fn step_over_call(&mut self) {
+        let return_address = self.machine.register(PROGRAM_COUNTER_REG_NUM);
+        let stack_pointer = self.machine.register(STACK_POINTER_REG_NUM);
+        self.run_instruction();
+        while self.running()
+            && !self.stopped()
+            && !(self.next_address() == return_address
+                && self.machine.register(STACK_POINTER_REG_NUM) == stack_pointer)
+        {
+            self.run_instruction();
+        }
+    }
+
+    /// Runs one instruction, a call runs until it returns if over_calls is set.
+    /// A hit stops it early.
+    pub fn step_instruction(&mut self, over_calls: bool) {
+        self.hit = None;
+        let opcode = (self.machine.instruction() & OPCODE_MASK) >> OPCODE_SHIFT;
+        if over_calls && opcode == CALL as u32 && self.machine.at_instruction_boundary() {
+            self.step_over_call();
+        } else {
+            self.run_instruction();
+        }
+    }
+
+    fn step(&mut self, count: u32, over_calls: bool) -> String {
+        for _ in 0..count {
+            if !self.running() {
+                break;
+            }
+            self.step_instruction(over_calls);
+            if self.stopped() {
+                break;
+            }
+        }
+        self.stop_message()
+    }
+
+    fn micro(&mut self, count: u32) -> String {
+        let mut ret = Vec::new();
+        for _ in 0..count {
+            if !self.running() {
+                break;
+            }
+            self.hit = None;
+            self.step_cycle();
+            ret.push(format!(
+                "step {}: {}",
+                self.machine.microcode_counter().saturating_sub(1),
+                self.machine.cables()
+            ));
+            if self.hit.is_some() {
+                break;
+            }
+        }
+        ret.push(self.stop_message());
+        ret.join("\n")
+    }
+
+    fn continue_running(&mut self) -> String {
+        self.hit = None;
+        while self.running() {
+            self.run_instruction();
+            if self.hit.is_some() {
+                break;
+            }
+            if self.at_breakpoint() {
+                return format!("breakpoint {}", self.stop_message());
+            }
+        }
+        self.stop_message()
+    }
+
+    /// Lists the breakpoints and the numbered stops.
+    fn list_stops(&self) -> String {
+        let breakpoints = self.breakpoints.iter().map(|a| self.describe(*a));
+        let stops = self.stops.iter().enumerate().map(|(i, stop)| format!("#{} {}", i + 1, stop));
+        breakpoints.chain(stops).collect::<Vec<_>>().join("\n")
+    }
+
+    fn watch(&mut self, words: &[&str], access: Access) -> Result<String, String> {
+        let start = self.location(words.get(1).ok_or("watch needs a location")?)?;
+        let count = match words.get(2) {
+            Some(w) => parse_number(w).ok_or(format!("not a number: {}", w))?,
+            None => 1,
+        };
+        self.add_stop(Stop::Watch(Watchpoint {
+            addresses: start..start.saturating_add(count),
+            access,
+        }));
+        Ok(format!("#{} {}", self.stops.len(), self.stops.last().unwrap()))
+    }
+
+    fn registers(&self) -> String {
+        let mut ret: Vec<String> = (0..REGISTERS_NUM)
+            .map(|r| format!("{:<5} {}", register_name(r).unwrap(), self.machine.register(r)))
+            .collect();
+        ret.push(format!("{:<5} {:#b}", "flags", self.machine.flags()));
+        ret.join("\n")
+    }
+
+    fn examine(&self, address: u32, count: u32) -> String {
+        (0..count)
+            .map_while(|i| address.checked_add(i))
+            .map(|a| {
+                let value = self.machine.read_ram(a);
+                format!("{}: {:#x} {}", a, value, value)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Setting pc also fetches the instruction there, so it runs next.
+    fn set(&mut self, target: &str, value: u32) -> Result<String, String> {
+        match register_number(target) {
+            Some(PROGRAM_COUNTER_REG_NUM) => self.machine.jump(value),
+            Some(reg_num) => self.machine.set_register(reg_num, value),
+            None => self.machine.write_ram(self.location(target)?, value),
+        }
+        Ok(String::new())
+    }
+
+    fn disassemble(&self, around: u32) -> String {
+        let mut ret = Vec::new();
+        let mut address = around.saturating_sub(DISASSEMBLE_AROUND);
+        let end = around.saturating_add(DISASSEMBLE_AROUND);
+        while address <= end {
+            let marker = if self.running() && address == self.next_address() {
+                "=>"
+            } else if self.breakpoints.contains(&address) {
+                " *"
+            } else {
+                "  "
+            };
+            ret.push(format!("{} {}", marker, self.describe(address)));
+            let instruction = self.machine.read_instruction(address);
+            // the listing ends at the highest address instead of wrapping
+            let Some(next) = address.checked_add(INSTRUCTION_WORDS as u32) else {
+                break;
+            };
+            address = next;
+            if (instruction & OPCODE_MASK) >> OPCODE_SHIFT == LDCNST as u32 {
+                ret.push(format!("   {}: constant {}", address, self.machine.read_ram(address)));
+                let Some(next) = address.checked_add(1) else {
+                    break;
+                };
+                address = next;
+            }
+        }
+        ret.join("\n")
+    }
+
+    /// Runs one command line and returns its output.
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = |i: usize, default: u32| match words.get(i) {
+            Some(w) => parse_number(w).ok_or(format!("not a number: {}", w)),
+            None => Ok(default),
+        };
+        match words.first().copied() {
+            Some("step" | "s") => Ok(self.step(count(1, 1)?, false)),
+            Some("next" | "n") => Ok(self.step(count(1, 1)?, true)),
+            Some("micro" | "m") => Ok(self.micro(count(1, 1)?)),
+            Some("continue" | "c") => Ok(self.continue_running()),
+            Some("break" | "b") => match words.get(1) {
+                Some(location) => {
+                    let address = self.location(location)?;
+                    self.breakpoints.insert(address);
+                    Ok(format!("breakpoint at {}", self.describe(address)))
+                }
+                None => Ok(self.list_stops()),
+            },
+            Some("watch") => self.watch(&words, Access::Write),
+            Some("rwatch") => self.watch(&words, Access::Read),
+            Some("awatch") => self.watch(&words, Access::ReadWrite),
+            Some("cbreak") => {
+                let name = words.get(1).ok_or("cbreak needs a cable")?;
+                let cable = cable_number(name).ok_or(format!("unknown cable: {}", name))?;
+                self.add_stop(Stop::Cable(cable));
+                Ok(format!("#{} {}", self.stops.len(), self.stops.last().unwrap()))
+            }
+            Some("cond") => match words[1..] {
+                [target, comparison, value] => {
+                    let target = match target {
+                        "flags" => REGISTERS_NUM,
+                        name => register_number(name).ok_or(format!("unknown register: {}", name))?,
+                    };
+                    let comparison = Comparison::parse(comparison)
+                        .ok_or(format!("unknown comparison: {}", comparison))?;
+                    let value = self.location(value)?;
+                    self.add_stop(Stop::Condition(target, comparison, value));
+                    Ok(format!("#{} {}", self.stops.len(), self.stops.last().unwrap()))
+                }
+                _ => Err("cond needs a register, a comparison and a value".to_string()),
+            },
+            Some("delete" | "d") => match words.get(1) {
+                Some(number) if number.starts_with('#') => {
+                    let i = parse_number(&number[1..]).ok_or(format!("not a number: {}", number))?;
+                    match (i as usize).checked_sub(1).and_then(|i| self.remove_stop(i)) {
+                        Some(_) => Ok(String::new()),
+                        None => Err(format!("no stop {}", number)),
+                    }
+                }
+                Some(location) => {
+                    let address = self.location(location)?;
+                    if self.breakpoints.remove(&address) {
+                        Ok(String::new())
+                    } else {
+                        Err(format!("no breakpoint at {}", address))
+                    }
+                }
+                None => {
+                    self.breakpoints.clear();
+                    self.stops.clear();
+                    self.conditions_held.clear();
+                    self.update_watchpoints();
+                    Ok(String::new())
+                }
+            },
+            Some("regs" | "r") => Ok(self.registers()),
+            Some("x") => {
+                let address = self.location(words.get(1).ok_or("x needs a location")?)?;
+                let count = count(2, EXAMINE_WORDS)?;
+                if count > EXAMINE_MAX_WORDS {
+                    return Err(format!("x prints at most {} words", EXAMINE_MAX_WORDS));
+                }
+                Ok(self.examine(address, count))
+            }
+            Some("set") => match words[1..] {
+                [target, value] => {
+                    let value = self.location(value)?;
+                    self.set(target, value)
+                }
+                _ => Err("set needs a target and a value".to_string()),
+            },
+            Some("disas") => {
+                let around = match words.get(1) {
+                    Some(location) => self.location(location)?,
+                    None => self.next_address(),
+                };
+                Ok(self.disassemble(around))
+            }
+            Some("snapshot") => {
+                let path = words.get(1).ok_or("snapshot needs a file")?;
+                self.machine.snapshot().save(path).map_err(|e| e.to_string())?;
+                Ok(String::new())
+            }
+            Some("restore") => {
+                let path = words.get(1).ok_or("restore needs a file")?;
+                let snapshot = Snapshot::load(path).map_err(|e| e.to_string())?;
+                self.machine.restore(&snapshot)?;
+                self.hit = None;
+                Ok(self.stop_message())
+            }
+            Some("components") => {
+                self.machine.step_print();
+                Ok(String::new())
+            }
+            Some("help" | "h") => Ok(HELP.to_string()),
+            Some(command) => Err(format!("unknown command: {}, try help", command)),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Reads commands until quit or the end of the input.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> std::io::Result<()> {
+        writeln!(output, "{}", self.stop_message())?;
+        let mut last = String::new();
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(mmdb) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            if line == "quit" || line == "q" {
+                return Ok(());
+            }
+            match self.execute(&line) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{}", text)?,
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+            last = line;
+        }
+    }
+}
@@ -2,10 +2,9 @@
 use std::{collections::HashMap};
 use crate::decode::INSTRUCTION::*;
 
-use crate::microcodes::{OPCODE_MASK, SOURCE_MASK, DEST_MASK, INSTRUCTION, REG, OPCODE_SHIFT, SOURCE_SHIFT};
+use crate::microcodes::{OPCODE_MASK, SOURCE_MASK, DEST_MASK, INSTRUCTION, REG, OPCODE_SHIFT, SOURCE_SHIFT, DEST_SHIFT};
 use crate::{ControlCable, ControlCables};
 use crate::ControlCable::*;
-use crate::CONTROL_CABLES_SIZE;
 
 fn mnemonic_names() -> HashMap<INSTRUCTION, &'static str> {
     let mut ret = HashMap::new();
@@ -33,6 +32,29 @@ fn mnemonic_names() -> HashMap<INSTRUCTION, &'static str> {
     ret.insert(LOAD, "load");
     ret.insert(STORE, "store");
     ret.insert(LDCNST, "ldcnst");
+    ret.insert(STI, "sti");
+    ret.insert(CLI, "cli");
+    ret.insert(JC, "jc");
+    ret.insert(JO, "jo");
+    ret.insert(JZ, "jz");
+    ret.insert(JS, "js");
+    ret.insert(IMUL, "imul");
+    ret.insert(IDIV, "idiv");
+    ret.insert(JGS, "jgs");
+    ret.insert(JGES, "jges");
+    ret.insert(JLS, "jls");
+    ret.insert(JLES, "jles");
+    ret.insert(AND, "and");
+    ret.insert(OR, "or");
+    ret.insert(XOR, "xor");
+    ret.insert(NOT, "not");
+    ret.insert(SHL, "shl");
+    ret.insert(SHR, "shr");
+    ret.insert(SAR, "sar");
+    ret.insert(ROL, "rol");
+    ret.insert(ROR, "ror");
+    ret.insert(MOD, "mod");
+    ret.insert(DIVMOD, "divmod");
     ret
 }
 
@@ -56,10 +78,18 @@ fn cable_names() -> HashMap<ControlCable, &'static str> {
     ret.insert(RamIn,"RamIn");
     ret.insert(RamOut,"RamOut");
     ret.insert(MemoryIsIO,"MemoryIsIO");
-    ret.insert(AddMul,"AddMul");
-    ret.insert(SubDiv,"SubDiv");
+    ret.insert(AluSelect0,"AluSelect0");
+    ret.insert(AluSelect1,"AluSelect1");
+    ret.insert(AluSelect2,"AluSelect2");
+    ret.insert(AluSelect3,"AluSelect3");
+    ret.insert(AluSelect4,"AluSelect4");
     ret.insert(AluOut,"AluOut");
     ret.insert(Interrupt,"Interrupt");
+    ret.insert(InterruptEnable,"InterruptEnable");
+    ret.insert(InterruptDisable,"InterruptDisable");
+    ret.insert(InterruptAcknowledge,"InterruptAcknowledge");
+    ret.insert(DivideErrorVector,"DivideErrorVector");
+    ret.insert(InvalidOpcodeVector,"InvalidOpcodeVector");
     ret
 }
 
@@ -74,29 +104,60 @@ fn op_names() -> HashMap<usize, &'static str> {
 
 pub fn dump_cables(cables: &ControlCables) -> String {
     let mut ret = String::new();
-    for i in 0..CONTROL_CABLES_SIZE {
-        if cables[i].load(std::sync::atomic::Ordering::SeqCst) {
-            if i < RegBase as usize{
-                ret.push_str(cable_names()[&num::FromPrimitive::from_usize(i).unwrap()]);
-                ret.push_str(" ");
-            } else {
-                let reg_num = (i - RegBase as usize)/4;
-                let reg_op = (i - RegBase as usize)%4;
-                let reg_name = reg_names()[&num::FromPrimitive::from_usize(reg_num).unwrap()];
-                let op_name = op_names()[&reg_op];
-                ret.push_str(format!("{}_{} ", reg_name, op_name).as_str());
-            }
+    for (i, cable) in cables.iter().enumerate() {
+        if cable.load(std::sync::atomic::Ordering::SeqCst) {
+            ret.push_str(&cable_name(i));
+            ret.push(' ');
         }
     }
     ret
 }
 
+/// The name of the cable at an index of `ControlCables`.
+pub fn cable_name(i: usize) -> String {
+    if i < RegBase as usize {
+        cable_names()[&num::FromPrimitive::from_usize(i).unwrap()].to_string()
+    } else {
+        let reg_num = (i - RegBase as usize)/4;
+        let reg_op = (i - RegBase as usize)%4;
+        let reg_name = reg_names()[&num::FromPrimitive::from_usize(reg_num).unwrap()];
+        let op_name = op_names()[&reg_op];
+        format!("{}_{}", reg_name, op_name)
+    }
+}
+
+pub fn register_name(reg_num: usize) -> Option<&'static str> {
+    let reg: REG = num::FromPrimitive::from_usize(reg_num)?;
+    Some(reg_names()[&reg])
+}
+
+pub fn register_number(name: &str) -> Option<usize> {
+    reg_names().into_iter().find(|(_, n)| *n == name).map(|(reg, _)| reg as usize)
+}
+
+/// The index in `ControlCables` of a cable named like in `dump_cables`.
+pub fn cable_number(name: &str) -> Option<usize> {
+    if let Some((cable, _)) = cable_names().into_iter().find(|(_, n)| *n == name) {
+        return Some(cable as usize);
+    }
+    let (reg_name, op_name) = name.split_once('_')?;
+    let reg_num = register_number(reg_name)?;
+    let (reg_op, _) = op_names().into_iter().find(|(_, n)| *n == op_name)?;
+    Some(RegBase as usize + reg_num * 4 + reg_op)
+}
+
 pub fn decode_instruction(instr: u32) -> String {
     let op_num = (instr & OPCODE_MASK) >> OPCODE_SHIFT;
     let src_num = (instr & SOURCE_MASK) >> SOURCE_SHIFT;
-    let dst_num = instr & DEST_MASK;
-    let op: INSTRUCTION = num::FromPrimitive::from_u32(op_num).unwrap();
-    let src: REG = num::FromPrimitive::from_u32(src_num).unwrap();
-    let dst: REG = num::FromPrimitive::from_u32(dst_num).unwrap();
-    format!("{} {} {}", mnemonic_names().get(&op).unwrap(), reg_names().get(&src).unwrap(), reg_names().get(&dst).unwrap())
+    let dst_num = (instr & DEST_MASK) >> DEST_SHIFT;
+    // undefined opcodes and registers show their number
+    let op = match num::FromPrimitive::from_u32(op_num) {
+        Some(op) => mnemonic_names()[&op].to_string(),
+        None => format!("invalid({:#08b})", op_num),
+    };
+    let reg = |num: u32| match num::FromPrimitive::from_u32(num) {
+        Some(reg) => reg_names()[&reg].to_string(),
+        None => format!("r{}", num),
+    };
+    format!("{} {} {}", op, reg(src_num), reg(dst_num))
 }
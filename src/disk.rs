@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::bits::{words_from_bytes, words_to_bytes, WORD_BYTES};
+use crate::io::IoDevice;
+
+pub const SECTOR_WORDS: usize = 256;
+const SECTOR_BYTES: usize = SECTOR_WORDS * WORD_BYTES;
+
+pub const DISK_PORTS: Range<u32> = 0x60..0x66;
+pub const DISK_IRQ_LINE: usize = 2;
+
+/// read/write: the sector used by the next command
+pub const DISK_SECTOR_PORT: u32 = 0x60;
+/// read/write: the word of the sector buffer at the buffer index, moves the
+/// index to the next word
+pub const DISK_DATA_PORT: u32 = 0x61;
+/// write: DISK_READ or DISK_WRITE
+pub const DISK_COMMAND_PORT: u32 = 0x62;
+/// read: DISK_ERROR if the last command failed
+pub const DISK_STATUS_PORT: u32 = 0x63;
+/// read: the number of sectors of the disk
+pub const DISK_SIZE_PORT: u32 = 0x64;
+/// read/write: the buffer index, commands reset it to 0
+pub const DISK_INDEX_PORT: u32 = 0x65;
+
+/// loads the selected sector into the buffer
+pub const DISK_READ: u32 = 1;
+/// stores the buffer into the selected sector
+pub const DISK_WRITE: u32 = 2;
+
+pub const DISK_ERROR: u32 = 1;
+
+struct DiskState {
+    file: File,
+    sectors: u32,
+    sector: u32,
+    index: usize,
+    buffer: [u32; SECTOR_WORDS],
+    status: u32,
+}
+
+/// A disk made of 256 word sectors, backed by an image file holding big endian
+/// words like the binary files. Every command requests an interrupt when done.
+pub struct BlockDevice {
+    state: Mutex<DiskState>,
+    irq_request: AtomicBool,
+}
+
+impl BlockDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Arc<Self>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let sectors = file.metadata()?.len().div_ceil(SECTOR_BYTES as u64) as u32;
+        Ok(Arc::new(BlockDevice {
+            state: Mutex::new(DiskState {
+                file,
+                sectors,
+                sector: 0,
+                index: 0,
+                buffer: [0; SECTOR_WORDS],
+                status: 0,
+            }),
+            irq_request: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.state.lock().sectors
+    }
+
+    fn command(state: &mut DiskState, command: u32) -> std::io::Result<()> {
+        if state.sector >= state.sectors {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        state
+            .file
+            .seek(SeekFrom::Start(state.sector as u64 * SECTOR_BYTES as u64))?;
+        match command {
+            DISK_READ => {
+                // the last sector of the image can be short
+                let mut bytes = [0; SECTOR_BYTES];
+                let mut read = 0;
+                loop {
+                    match state.file.read(&mut bytes[read..])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                state.buffer.copy_from_slice(&words_from_bytes(&bytes));
+            }
+            DISK_WRITE => {
+                state.file.write_all(&words_to_bytes(&state.buffer))?;
+                state.file.flush()?;
+            }
+            _ => return Err(std::io::ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+}
+
+impl IoDevice for BlockDevice {
+    fn read(&self, port: u32) -> u32 {
+        let mut state = self.state.lock();
+        match port {
+            DISK_SECTOR_PORT => state.sector,
+            DISK_DATA_PORT => {
+                let ret = state.buffer[state.index];
+                state.index = (state.index + 1) % SECTOR_WORDS;
+                ret
+            }
+            DISK_STATUS_PORT => state.status,
+            DISK_SIZE_PORT => state.sectors,
+            DISK_INDEX_PORT => state.index as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, port: u32, value: u32) {
+        let mut state = self.state.lock();
+        match port {
+            DISK_SECTOR_PORT => state.sector = value,
+            DISK_DATA_PORT => {
+                let index = state.index;
+                state.buffer[index] = value;
+                state.index = (index + 1) % SECTOR_WORDS;
+            }
+            DISK_COMMAND_PORT => {
+                state.index = 0;
+                state.status = match Self::command(&mut state, value) {
+                    Ok(()) => 0,
+                    Err(_) => DISK_ERROR,
+                };
+                self.irq_request.store(true, SeqCst);
+            }
+            DISK_INDEX_PORT => state.index = value as usize % SECTOR_WORDS,
+            _ => {}
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_request.swap(false, SeqCst)
+    }
+}
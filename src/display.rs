@@ -0,0 +1,175 @@
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+
+use parking_lot::Mutex;
+
+use crate::io::IoDevice;
+
+pub const DISPLAY_PORTS: Range<u32> = 0x80..0x85;
+
+/// read/write: the cursor, row * width + column
+pub const DISPLAY_CURSOR_PORT: u32 = 0x80;
+/// write: puts a character at the cursor and moves it, read: the character at
+/// the cursor
+pub const DISPLAY_DATA_PORT: u32 = 0x81;
+/// write: DISPLAY_CLEAR
+pub const DISPLAY_CONTROL_PORT: u32 = 0x82;
+/// read: the number of columns
+pub const DISPLAY_WIDTH_PORT: u32 = 0x83;
+/// read: the number of rows
+pub const DISPLAY_HEIGHT_PORT: u32 = 0x84;
+
+/// blanks the screen and moves the cursor home
+pub const DISPLAY_CLEAR: u32 = 1;
+
+pub const DEFAULT_DISPLAY_WIDTH: usize = 80;
+pub const DEFAULT_DISPLAY_HEIGHT: usize = 25;
+
+struct Screen {
+    cells: Vec<u8>,
+    cursor: usize,
+}
+
+/// A text mode framebuffer reachable through a port window. Writing a newline
+/// moves the cursor to the next row, the screen scrolls when the cursor moves
+/// past the last cell.
+pub struct TextDisplay {
+    width: usize,
+    height: usize,
+    screen: Mutex<Screen>,
+    dirty: AtomicBool,
+    /// render to the terminal every this many cycles, 0 disables rendering
+    refresh_cycles: u32,
+    cycles: AtomicU32,
+}
+
+impl TextDisplay {
+    /// Panics if width or height is 0, the cursor would have nowhere to go.
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(
+            width > 0 && height > 0,
+            "the display can't be empty, got {}x{}",
+            width,
+            height
+        );
+        TextDisplay {
+            width,
+            height,
+            screen: Mutex::new(Screen {
+                cells: vec![b' '; width * height],
+                cursor: 0,
+            }),
+            dirty: AtomicBool::new(false),
+            refresh_cycles: 0,
+            cycles: AtomicU32::new(0),
+        }
+    }
+
+    /// Redraws the terminal from the device tick when the screen changed.
+    pub fn with_terminal(mut self, refresh_cycles: u32) -> Self {
+        self.refresh_cycles = refresh_cycles;
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.screen.lock().cursor
+    }
+
+    fn put(&self, screen: &mut Screen, c: u8) {
+        if c == b'\n' {
+            screen.cursor = (screen.cursor / self.width + 1) * self.width;
+        } else {
+            screen.cells[screen.cursor] = c;
+            screen.cursor += 1;
+        }
+        if screen.cursor >= screen.cells.len() {
+            screen.cells.drain(..self.width);
+            screen.cells.resize(self.width * self.height, b' ');
+            screen.cursor -= self.width;
+        }
+    }
+
+    /// The screen as text, one line per row without trailing blanks.
+    pub fn snapshot(&self) -> String {
+        let screen = self.screen.lock();
+        let mut ret = String::new();
+        for row in screen.cells.chunks(self.width) {
+            let line: String = row.iter().map(|c| *c as char).collect();
+            ret.push_str(line.trim_end());
+            ret.push('\n');
+        }
+        ret
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.snapshot())
+    }
+
+    /// Draws the whole screen with ANSI escapes and puts the terminal cursor
+    /// at the display cursor.
+    pub fn render<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let screen = self.screen.lock();
+        write!(out, "\x1b[H")?;
+        for row in screen.cells.chunks(self.width) {
+            out.write_all(row)?;
+            write!(out, "\x1b[K\r\n")?;
+        }
+        let row = screen.cursor / self.width + 1;
+        let column = screen.cursor % self.width + 1;
+        write!(out, "\x1b[{};{}H", row, column)?;
+        out.flush()
+    }
+}
+
+impl IoDevice for TextDisplay {
+    fn read(&self, port: u32) -> u32 {
+        let screen = self.screen.lock();
+        match port {
+            DISPLAY_CURSOR_PORT => screen.cursor as u32,
+            DISPLAY_DATA_PORT => screen.cells[screen.cursor] as u32,
+            DISPLAY_WIDTH_PORT => self.width as u32,
+            DISPLAY_HEIGHT_PORT => self.height as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, port: u32, value: u32) {
+        let mut screen = self.screen.lock();
+        match port {
+            DISPLAY_CURSOR_PORT => screen.cursor = value as usize % screen.cells.len(),
+            DISPLAY_DATA_PORT => self.put(&mut screen, value as u8),
+            DISPLAY_CONTROL_PORT => {
+                if value & DISPLAY_CLEAR != 0 {
+                    screen.cells.fill(b' ');
+                    screen.cursor = 0;
+                }
+            }
+            _ => return,
+        }
+        self.dirty.store(true, SeqCst);
+    }
+
+    fn tick(&self) {
+        if self.refresh_cycles == 0 {
+            return;
+        }
+        if self.cycles.fetch_add(1, SeqCst) + 1 < self.refresh_cycles {
+            return;
+        }
+        self.cycles.store(0, SeqCst);
+        if self.dirty.swap(false, SeqCst) {
+            self.render(&mut std::io::stdout()).unwrap();
+        }
+    }
+}
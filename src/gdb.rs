@@ -0,0 +1,392 @@
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::bits::{BITNESS, WORD_BYTES, WORD_MASK};
+use crate::cpu_component::{
+    INSTRUCTION_REG_NUM, PROGRAM_COUNTER_REG_NUM, REGISTERS_NUM, STACK_POINTER_REG_NUM,
+};
+use crate::decode::register_name;
+use crate::interrupts::Exception;
+use crate::machine::{Machine, MachineStatus};
+
+/// the flags follow the registers in the register packets
+const FLAGS_REG_NUM: usize = REGISTERS_NUM;
+/// instructions run by continue between checks for an interrupt from gdb
+const INTERRUPT_CHECK_INSTRUCTIONS: u32 = 1000;
+const PACKET_SIZE: usize = 0x1000;
+/// the most words an `m` reply holds, two hex digits a byte
+const MAX_READ_WORDS: u32 = (PACKET_SIZE / (2 * WORD_BYTES)) as u32;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+
+fn word_hex(value: u32) -> String {
+    format!("{:0width$x}", value, width = WORD_BYTES * 2)
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Splits hex digits into big endian words.
+fn parse_words(s: &str) -> Option<Vec<u32>> {
+    if !s.len().is_multiple_of(WORD_BYTES * 2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(WORD_BYTES * 2)
+        .map(|i| s.get(i..i + WORD_BYTES * 2).and_then(parse_hex))
+        .collect()
+}
+
+/// The target description served as `target.xml`, the registers in the order
+/// of the register packets at the word width of the build.
+fn target_xml() -> String {
+    let registers: String = (0..=FLAGS_REG_NUM)
+        .map(|r| {
+            let kind = match r {
+                PROGRAM_COUNTER_REG_NUM => "code_ptr",
+                STACK_POINTER_REG_NUM => "data_ptr",
+                _ => "int",
+            };
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>",
+                register_name(r).unwrap_or("flags"),
+                BITNESS,
+                kind,
+                r
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.mmachine.core\">{}</feature></target>",
+        registers
+    )
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+/// A gdb remote serial protocol server driving a machine one instruction at a
+/// time, in place of the free running clock.
+///
+/// Memory is word addressed, so addresses and lengths of the memory packets
+/// count words and every word is sent as big endian bytes. The registers are
+/// a, b, c, d, e, pc, sp, inst and flags. Like in the debugger prompt, pc is
+/// the address of the instruction about to run and writing it jumps there.
+pub struct GdbStub {
+    machine: Machine,
+    breakpoints: BTreeSet<u32>,
+    stop_reason: u8,
+}
+
+impl GdbStub {
+    /// Runs the first fetch, or the rest of a restored instruction, stopping
+    /// before the next instruction.
+    pub fn new(mut machine: Machine) -> Self {
+        if !machine.at_instruction_boundary() {
+            machine.step_instruction();
+        }
+        GdbStub {
+            machine,
+            breakpoints: BTreeSet::new(),
+            stop_reason: SIGTRAP,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Waits for gdb to connect to address and serves it until it detaches or
+    /// kills the program.
+    pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> std::io::Result<()> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        self.serve(stream)
+    }
+
+    pub fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut last_reply = String::new();
+        loop {
+            let packet = match Self::read_packet(&mut stream)? {
+                Some(Ok(packet)) => packet,
+                Some(Err(resend)) => {
+                    if resend {
+                        Self::write_packet(&mut stream, &last_reply)?;
+                    } else {
+                        stream.write_all(b"-")?;
+                    }
+                    continue;
+                }
+                None => return Ok(()),
+            };
+            stream.write_all(b"+")?;
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => self.resume(&packet[1..], Some(&mut stream))?,
+                _ => match self.handle(&packet) {
+                    Some(reply) => reply,
+                    None => {
+                        // kill has no reply
+                        if packet.starts_with('D') {
+                            Self::write_packet(&mut stream, "OK")?;
+                        }
+                        return Ok(());
+                    }
+                },
+            };
+            Self::write_packet(&mut stream, &reply)?;
+            last_reply = reply;
+        }
+    }
+
+    /// Reads the next packet, an error asks for a resend, of the last reply if
+    /// true and of the packet otherwise. None at the end of the connection.
+    fn read_packet(stream: &mut TcpStream) -> std::io::Result<Option<Result<String, bool>>> {
+        let mut byte = [0];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => break,
+                b'-' => return Ok(Some(Err(true))),
+                // acks and interrupts while stopped
+                _ => {}
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let valid = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+            == Some(checksum(&data));
+        Ok(Some(if valid { Ok(data) } else { Err(false) }))
+    }
+
+    fn write_packet(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+        write!(stream, "${}#{:02x}", data, checksum(data))?;
+        stream.flush()
+    }
+
+    /// Whether gdb sent an interrupt, without waiting for one.
+    fn interrupted(stream: &mut TcpStream) -> std::io::Result<bool> {
+        stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let ret = match stream.read(&mut byte) {
+            Ok(n) => n == 1 && byte[0] == 0x03,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(ret)
+    }
+
+    /// The reply telling gdb why the machine stopped.
+    pub fn stop_reply(&self) -> String {
+        match self.machine.status() {
+            MachineStatus::Running => format!("S{:02x}", self.stop_reason),
+            MachineStatus::Halted => format!("W{:02x}", self.machine.exit_code() & 0xff),
+            MachineStatus::Faulted(Exception::DivideError) => format!("S{:02x}", SIGFPE),
+            MachineStatus::Faulted(Exception::InvalidOpcode) => format!("S{:02x}", SIGILL),
+            MachineStatus::BusError(_) => format!("S{:02x}", SIGBUS),
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.machine.status() == MachineStatus::Running
+            && self.breakpoints.contains(&self.machine.next_address())
+    }
+
+    /// Continues from address, or from where the machine stopped, until a
+    /// breakpoint, the end of the program or an interrupt read from stream.
+    fn resume(
+        &mut self,
+        address: &str,
+        mut stream: Option<&mut TcpStream>,
+    ) -> std::io::Result<String> {
+        if let Some(address) = parse_hex(address) {
+            self.machine.jump(address);
+        }
+        self.stop_reason = SIGTRAP;
+        let mut instructions: u32 = 0;
+        while self.machine.step_instruction() == MachineStatus::Running && !self.at_breakpoint() {
+            instructions += 1;
+            if instructions.is_multiple_of(INTERRUPT_CHECK_INSTRUCTIONS) {
+                if let Some(stream) = stream.as_deref_mut() {
+                    if Self::interrupted(stream)? {
+                        self.stop_reason = SIGINT;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(self.stop_reply())
+    }
+
+    fn register(&self, reg_num: usize) -> Option<u32> {
+        match reg_num {
+            PROGRAM_COUNTER_REG_NUM => Some(self.machine.next_address()),
+            // the flags above the word width are left out
+            FLAGS_REG_NUM => Some(self.machine.flags() & WORD_MASK),
+            r if r < REGISTERS_NUM => Some(self.machine.register(r)),
+            _ => None,
+        }
+    }
+
+    fn set_register(&self, reg_num: usize, value: u32) -> bool {
+        let value = value & WORD_MASK;
+        match reg_num {
+            PROGRAM_COUNTER_REG_NUM => self.machine.jump(value),
+            FLAGS_REG_NUM => self.machine.set_flags(value | self.machine.flags() & !WORD_MASK),
+            r if r < REGISTERS_NUM => self.machine.set_register(r, value),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = args.split_once(',')?;
+        let address = parse_hex(address)?;
+        let length = parse_hex(length)?;
+        if length > MAX_READ_WORDS {
+            return None;
+        }
+        Some(
+            (0..length)
+                .map(|i| word_hex(self.machine.read_ram(address.wrapping_add(i))))
+                .collect(),
+        )
+    }
+
+    fn write_memory(&self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = range.split_once(',')?;
+        let address = parse_hex(address)?;
+        let words = parse_words(data)?;
+        if words.len() != parse_hex(length)? as usize {
+            return None;
+        }
+        for (i, word) in words.into_iter().enumerate() {
+            self.machine.write_ram(address.wrapping_add(i as u32), word);
+        }
+        Some(())
+    }
+
+    /// Parses the address of a `Z0`/`z0` packet. The kind is ignored, a
+    /// breakpoint matches the first word of an instruction however many words
+    /// it takes.
+    fn breakpoint_address(args: &str) -> Option<u32> {
+        let mut fields = args.split(',');
+        match (fields.next(), fields.next()) {
+            (Some("0"), Some(address)) => parse_hex(address),
+            _ => None,
+        }
+    }
+
+    /// Answers `qXfer:features:read:target.xml:offset,length`, `m` marks a
+    /// part with more to follow and `l` the last one.
+    fn read_features(args: &str) -> Option<String> {
+        let range = args.strip_prefix("target.xml:")?;
+        let (offset, length) = range.split_once(',')?;
+        let xml = target_xml();
+        let start = (parse_hex(offset)? as usize).min(xml.len());
+        let end = start.saturating_add(parse_hex(length)? as usize).min(xml.len());
+        let more = if end < xml.len() { 'm' } else { 'l' };
+        Some(format!("{}{}", more, &xml[start..end]))
+    }
+
+    /// Answers a packet, continuing can't be interrupted from here. An empty
+    /// reply means the packet isn't supported, None ends the session.
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        let error = || "E01".to_string();
+        let ok = |done: bool| if done { "OK".to_string() } else { error() };
+        let command = packet.get(..1).unwrap_or_default();
+        let args = packet.get(1..).unwrap_or_default();
+        let reply = match command {
+            "?" => self.stop_reply(),
+            "g" => (0..=FLAGS_REG_NUM)
+                .map(|r| word_hex(self.register(r).unwrap()))
+                .collect(),
+            "G" => match parse_words(args) {
+                Some(values) if values.len() == FLAGS_REG_NUM + 1 => {
+                    // the instruction register follows a jump, so it is left alone
+                    for (r, value) in values.into_iter().enumerate() {
+                        if r != INSTRUCTION_REG_NUM {
+                            self.set_register(r, value);
+                        }
+                    }
+                    "OK".to_string()
+                }
+                _ => error(),
+            },
+            "p" => match parse_hex(args).and_then(|r| self.register(r as usize)) {
+                Some(value) => word_hex(value),
+                None => error(),
+            },
+            "P" => ok(args
+                .split_once('=')
+                .and_then(|(r, value)| Some((parse_hex(r)?, parse_hex(value)?)))
+                .is_some_and(|(r, value)| self.set_register(r as usize, value))),
+            "m" => self.read_memory(args).unwrap_or_else(error),
+            "M" => ok(self.write_memory(args).is_some()),
+            "s" => {
+                if let Some(address) = parse_hex(args) {
+                    self.machine.jump(address);
+                }
+                self.stop_reason = SIGTRAP;
+                self.machine.step_instruction();
+                self.stop_reply()
+            }
+            "c" => self.resume(args, None).unwrap(),
+            "Z" => match Self::breakpoint_address(args) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                    "OK".to_string()
+                }
+                None => String::new(),
+            },
+            "z" => match Self::breakpoint_address(args) {
+                Some(address) => {
+                    self.breakpoints.remove(&address);
+                    "OK".to_string()
+                }
+                None => String::new(),
+            },
+            "H" => "OK".to_string(),
+            "D" | "k" => return None,
+            _ => match packet {
+                _ if packet.starts_with("qSupported") => {
+                    format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+                }
+                _ if packet.starts_with("qXfer:features:read:") => {
+                    Self::read_features(&packet["qXfer:features:read:".len()..])
+                        .unwrap_or_else(|| "E00".to_string())
+                }
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            },
+        };
+        Some(reply)
+    }
+}
@@ -0,0 +1,316 @@
+use std::sync::Arc;
+
+use crate::bits::{sign_extend, BITNESS, SIGN_MASK, WORD_MASK};
+use crate::cpu_component::{
+    CARRY_BIT_NUM, EQUAL_BIT_NUM, GREATER_BIT_NUM, INSTRUCTION_REG_NUM,
+    INTERRUPT_ENABLE_BIT_NUM, INTERRUPT_VECTORS_NUM, INTERRUPT_VECTOR_TABLE, OVERFLOW_BIT_NUM,
+    PROGRAM_COUNTER_REG_NUM, RAM_SIZE, REGISTERS_NUM, SIGNED_GREATER_BIT_NUM, SIGN_BIT_NUM,
+    STACK_POINTER_REG_NUM, ZERO_BIT_NUM, ZERO_DIVISOR_BIT_NUM,
+};
+use crate::interrupts::{Exception, InterruptController};
+use crate::io::{ExitCode, PortMap};
+use crate::machine::MachineStatus;
+use crate::microcodes::INSTRUCTION::*;
+use crate::microcodes::{
+    shift_in_word, INSTRUCTION, DEST_MASK, DEST_SHIFT, INSTRUCTION_WORDS, OPCODE_MASK,
+    OPCODE_SHIFT, SOURCE_MASK, SOURCE_SHIFT,
+};
+use crate::timer::Timer;
+
+const PC: usize = PROGRAM_COUNTER_REG_NUM;
+const SP: usize = STACK_POINTER_REG_NUM;
+
+/// Executes whole instructions against a plain register file and RAM, without
+/// microcodes or components. It is the reference the microcoded cpu is tested
+/// against. Devices are ticked once per instruction instead of once per
+/// clock cycle, so timer reloads count instructions.
+pub struct Interpreter {
+    registers: [u32; REGISTERS_NUM],
+    interrupt_enable: bool,
+    /// carry, overflow, zero and sign of the last alu operation
+    alu_flags: u32,
+    ram: Vec<u32>,
+    ports: PortMap,
+    interrupt_controller: Arc<InterruptController>,
+    timer: Arc<Timer>,
+    exit_code: Arc<ExitCode>,
+    status: MachineStatus,
+    instructions: u64,
+}
+
+impl Interpreter {
+    pub(crate) fn new(
+        image: &[u32],
+        stack_pointer: u32,
+        ports: PortMap,
+        interrupt_controller: Arc<InterruptController>,
+        timer: Arc<Timer>,
+        exit_code: Arc<ExitCode>,
+    ) -> Self {
+        let mut registers = [0; REGISTERS_NUM];
+        registers[SP] = stack_pointer;
+        let mut ret = Interpreter {
+            registers,
+            interrupt_enable: true,
+            alu_flags: 0,
+            ram: vec![0; RAM_SIZE],
+            ports,
+            interrupt_controller,
+            timer,
+            exit_code,
+            status: MachineStatus::Running,
+            instructions: 0,
+        };
+        ret.load_image(image);
+        ret
+    }
+
+    /// Writes the image from address 0 like Machine::load_image, wrapping
+    /// around the RAM.
+    pub fn load_image(&mut self, image: &[u32]) {
+        for (i, word) in image.iter().enumerate() {
+            self.write_ram(i as u32, *word);
+        }
+    }
+
+    /// Serves a pending interrupt request or runs a single instruction.
+    pub fn step(&mut self) -> MachineStatus {
+        if self.status != MachineStatus::Running {
+            return self.status;
+        }
+        if self.interrupt_enable && self.interrupt_controller.active_line().is_some() {
+            let vector = self.interrupt_controller.acknowledge().unwrap();
+            self.push(self.registers[PC]);
+            self.jump_to_vector(vector as u32);
+        } else {
+            let mut instruction = 0;
+            for _ in 0..INSTRUCTION_WORDS {
+                let word = self.read_ram(self.registers[PC]);
+                instruction = shift_in_word(instruction, word);
+                self.registers[INSTRUCTION_REG_NUM] = word;
+                self.registers[PC] = self.registers[PC].wrapping_add(1) & WORD_MASK;
+            }
+            self.execute(instruction);
+            self.instructions += 1;
+        }
+        self.ports.tick(&self.interrupt_controller);
+        self.status
+    }
+
+    /// Runs until the program halts or faults.
+    pub fn run(&mut self) -> MachineStatus {
+        while self.step() == MachineStatus::Running {}
+        self.status
+    }
+
+    fn execute(&mut self, instruction: u32) {
+        let opcode = (instruction & OPCODE_MASK) >> OPCODE_SHIFT;
+        let src = ((instruction & SOURCE_MASK) >> SOURCE_SHIFT) as usize;
+        let dst = ((instruction & DEST_MASK) >> DEST_SHIFT) as usize;
+        let a = self.registers[0];
+        let b = self.registers[1];
+        let opcode: INSTRUCTION = match num::FromPrimitive::from_u32(opcode) {
+            Some(opcode) if src < REGISTERS_NUM && dst < REGISTERS_NUM => opcode,
+            _ => return self.raise(Exception::InvalidOpcode),
+        };
+        if matches!(opcode, DIV | IDIV | MOD | DIVMOD) && b == 0 {
+            return self.raise(Exception::DivideError);
+        }
+        match opcode {
+            HLT => self.status = MachineStatus::Halted,
+            MOV => self.registers[dst] = self.registers[src],
+            ADD => {
+                let sum = a as u64 + b as u64;
+                let overflow = (a ^ sum as u32) & (b ^ sum as u32) & SIGN_MASK != 0;
+                self.alu_result(dst, sum as u32, sum > WORD_MASK as u64, overflow);
+            }
+            SUB => {
+                let difference = a.wrapping_sub(b);
+                let overflow = (a ^ b) & (a ^ difference) & SIGN_MASK != 0;
+                self.alu_result(dst, difference, b > a, overflow);
+            }
+            MUL => {
+                let product = a as u64 * b as u64;
+                let overflow = product > WORD_MASK as u64;
+                self.alu_result(dst, product as u32, overflow, overflow);
+            }
+            DIV => self.alu_result(dst, a / b, false, false),
+            MOD => self.alu_result(dst, a % b, false, false),
+            DIVMOD => {
+                self.alu_result(dst, a / b, false, false);
+                self.alu_result(src, a % b, false, false);
+            }
+            IMUL | IDIV => {
+                let (a, b) = (sign_extend(a) as i64, sign_extend(b) as i64);
+                let result = if opcode == IMUL { a * b } else { a / b };
+                let overflow = sign_extend(result as u32) as i64 != result;
+                self.alu_result(dst, result as u32, overflow, overflow);
+            }
+            AND => self.alu_result(dst, a & b, false, false),
+            OR => self.alu_result(dst, a | b, false, false),
+            XOR => self.alu_result(dst, a ^ b, false, false),
+            NOT => self.alu_result(dst, !a, false, false),
+            SHL => {
+                let shifted = if (b as usize) < BITNESS { a << b } else { 0 };
+                self.alu_result(dst, shifted, false, false);
+            }
+            SHR => {
+                let shifted = if (b as usize) < BITNESS { a >> b } else { 0 };
+                self.alu_result(dst, shifted, false, false);
+            }
+            SAR => {
+                let shifted = sign_extend(a) >> b.min(BITNESS as u32 - 1);
+                self.alu_result(dst, shifted as u32, false, false);
+            }
+            ROL | ROR => {
+                let bits = BITNESS as u32;
+                let left = if opcode == ROL { b % bits } else { (bits - b % bits) % bits };
+                let rotated = if left == 0 { a } else { a << left | a >> (bits - left) };
+                self.alu_result(dst, rotated, false, false);
+            }
+            CALL => {
+                self.push(self.registers[PC]);
+                self.registers[PC] = self.registers[dst];
+            }
+            JE | JNE | JG | JGE | JL | JLE | JGS | JGES | JLS | JLES | JC | JO | JZ | JS => {
+                let taken = match opcode {
+                    JE => a == b,
+                    JNE => a != b,
+                    JG => a > b,
+                    JGE => a >= b,
+                    JL => a < b,
+                    JLE => a <= b,
+                    JGS => sign_extend(a) > sign_extend(b),
+                    JGES => sign_extend(a) >= sign_extend(b),
+                    JLS => sign_extend(a) < sign_extend(b),
+                    JLES => sign_extend(a) <= sign_extend(b),
+                    JC => self.alu_flags & (1 << CARRY_BIT_NUM) != 0,
+                    JO => self.alu_flags & (1 << OVERFLOW_BIT_NUM) != 0,
+                    JZ => self.alu_flags & (1 << ZERO_BIT_NUM) != 0,
+                    _ => self.alu_flags & (1 << SIGN_BIT_NUM) != 0,
+                };
+                if taken {
+                    self.registers[PC] = self.registers[dst];
+                }
+            }
+            PUSH => self.push(self.registers[src]),
+            POP => self.registers[dst] = self.pop(),
+            OUT => self.ports.write(self.registers[src], self.registers[dst]),
+            IN => self.registers[dst] = self.ports.read(self.registers[src]),
+            INT => {
+                if self.interrupt_enable {
+                    self.push(self.registers[PC]);
+                    self.jump_to_vector(self.registers[src]);
+                }
+            }
+            EOI => {
+                self.registers[PC] = self.pop();
+                self.interrupt_enable = true;
+            }
+            INC => self.registers[dst] = self.registers[dst].wrapping_add(1) & WORD_MASK,
+            DEC => self.registers[dst] = self.registers[dst].wrapping_sub(1) & WORD_MASK,
+            LOAD => self.registers[dst] = self.read_ram(self.registers[src]),
+            STORE => self.write_ram(self.registers[dst], self.registers[src]),
+            LDCNST => {
+                let constant = self.read_ram(self.registers[PC]);
+                if dst != PC {
+                    self.registers[PC] = self.registers[PC].wrapping_add(1) & WORD_MASK;
+                }
+                self.registers[dst] = constant;
+            }
+            STI => self.interrupt_enable = true,
+            CLI => self.interrupt_enable = false,
+        }
+    }
+
+    /// Stores an alu result and sets the flags.
+    fn alu_result(&mut self, dst: usize, result: u32, carry: bool, overflow: bool) {
+        let value = result & WORD_MASK;
+        self.registers[dst] = value;
+        self.alu_flags = (carry as u32) << CARRY_BIT_NUM
+            | (overflow as u32) << OVERFLOW_BIT_NUM
+            | ((value == 0) as u32) << ZERO_BIT_NUM
+            | ((value & SIGN_MASK != 0) as u32) << SIGN_BIT_NUM;
+    }
+
+    /// Runs the handler of the exception, or faults if there is none.
+    fn raise(&mut self, exception: Exception) {
+        if self.ram[INTERRUPT_VECTOR_TABLE + exception.vector()] == 0 {
+            self.status = MachineStatus::Faulted(exception);
+        } else {
+            self.push(self.registers[PC]);
+            self.jump_to_vector(exception.vector() as u32);
+        }
+    }
+
+    fn push(&mut self, value: u32) {
+        self.write_ram(self.registers[SP], value);
+        self.registers[SP] = self.registers[SP].wrapping_sub(1) & WORD_MASK;
+    }
+
+    fn pop(&mut self) -> u32 {
+        self.registers[SP] = self.registers[SP].wrapping_add(1) & WORD_MASK;
+        self.read_ram(self.registers[SP])
+    }
+
+    /// Disables interrupts and jumps to the handler, pc has to be pushed first.
+    fn jump_to_vector(&mut self, vector: u32) {
+        self.interrupt_enable = false;
+        let vector = vector as usize % INTERRUPT_VECTORS_NUM;
+        self.registers[PC] = self.ram[INTERRUPT_VECTOR_TABLE + vector];
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        self.status
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.status == MachineStatus::Halted
+    }
+
+    /// The number of instructions run so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn register(&self, reg_num: usize) -> u32 {
+        self.registers[reg_num]
+    }
+
+    pub fn set_register(&mut self, reg_num: usize, value: u32) {
+        self.registers[reg_num] = value & WORD_MASK;
+    }
+
+    /// The flags register as the microcoded cpu computes it.
+    pub fn flags(&self) -> u32 {
+        let a = self.registers[0];
+        let b = self.registers[1];
+        ((a == b) as u32) << EQUAL_BIT_NUM
+            | ((a > b) as u32) << GREATER_BIT_NUM
+            | ((sign_extend(a) > sign_extend(b)) as u32) << SIGNED_GREATER_BIT_NUM
+            | ((b == 0) as u32) << ZERO_DIVISOR_BIT_NUM
+            | (self.interrupt_enable as u32) << INTERRUPT_ENABLE_BIT_NUM
+            | self.alu_flags
+    }
+
+    pub fn read_ram(&self, address: u32) -> u32 {
+        self.ram[address as usize % RAM_SIZE]
+    }
+
+    pub fn write_ram(&mut self, address: u32, value: u32) {
+        self.ram[address as usize % RAM_SIZE] = value & WORD_MASK;
+    }
+
+    pub fn interrupt_controller(&self) -> &Arc<InterruptController> {
+        &self.interrupt_controller
+    }
+
+    pub fn timer(&self) -> &Arc<Timer> {
+        &self.timer
+    }
+
+    /// The last value written to EXIT_CODE_PORT.
+    pub fn exit_code(&self) -> u32 {
+        self.exit_code.code()
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use crate::bits::MValue;
+use crate::bus::Bus;
+use crate::cpu_component::{ControlCables, ControlCablesExt, CpuComponent};
+use crate::cpu_component::ControlCable::*;
+use crate::io::IoDevice;
+
+pub const IRQ_LINES_NUM: usize = 8;
+/// vector of irq line 0, the vectors below are left for exceptions and
+/// software interrupts
+pub const IRQ_VECTOR_BASE: usize = 32;
+
+/// Raised by an instruction instead of running it, through the vector of the
+/// same number. With no handler installed the machine stops as faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// DIV, IDIV, MOD or DIVMOD with b equal to 0
+    DivideError = 0,
+    /// an undefined opcode or register
+    InvalidOpcode = 1,
+}
+
+impl Exception {
+    pub fn vector(self) -> usize {
+        self as usize
+    }
+}
+
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exception::DivideError => write!(f, "divide error"),
+            Exception::InvalidOpcode => write!(f, "invalid opcode"),
+        }
+    }
+}
+
+pub const PIC_PORTS: std::ops::Range<u32> = 0x20..0x30;
+
+/// read: pending lines, write: clears the given pending lines
+pub const PIC_PENDING_PORT: u32 = 0x20;
+/// read/write: masked lines, a set bit masks the line
+pub const PIC_MASK_PORT: u32 = 0x21;
+/// read/write: priority of line n is at PIC_PRIORITY_PORT_BASE + n,
+/// lower values are served first
+pub const PIC_PRIORITY_PORT_BASE: u32 = 0x28;
+
+/// Collects interrupt requests from devices. The control component checks it
+/// between instructions and the controller puts the vector of the line being
+/// served on the bus when `InterruptAcknowledge` is asserted, or the vector of
+/// an exception when its cable is.
+pub struct InterruptController {
+    pending: AtomicU32,
+    mask: AtomicU32,
+    priorities: [AtomicU32; IRQ_LINES_NUM],
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptController {
+    /// All lines start masked, with priorities equal to their line numbers.
+    pub fn new() -> Self {
+        InterruptController {
+            pending: AtomicU32::new(0),
+            mask: AtomicU32::new((1 << IRQ_LINES_NUM) - 1),
+            priorities: array_init::array_init(|i| AtomicU32::new(i as u32)),
+        }
+    }
+
+    pub fn raise(&self, line: usize) {
+        self.pending.fetch_or(1 << line, SeqCst);
+    }
+
+    pub fn pending(&self) -> u32 {
+        self.pending.load(SeqCst)
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask.load(SeqCst)
+    }
+
+    pub fn set_mask(&self, mask: u32) {
+        self.mask.store(mask & ((1 << IRQ_LINES_NUM) - 1), SeqCst);
+    }
+
+    pub fn set_priority(&self, line: usize, priority: u32) {
+        self.priorities[line].store(priority, SeqCst);
+    }
+
+    /// The unmasked pending line with the highest priority.
+    pub fn active_line(&self) -> Option<usize> {
+        let requests = self.pending() & !self.mask();
+        (0..IRQ_LINES_NUM)
+            .filter(|line| requests & (1 << line) != 0)
+            .min_by_key(|line| self.priorities[*line].load(SeqCst))
+    }
+
+    /// Clears the active line and returns its vector.
+    pub fn acknowledge(&self) -> Option<usize> {
+        let line = self.active_line()?;
+        self.pending.fetch_and(!(1 << line), SeqCst);
+        Some(IRQ_VECTOR_BASE + line)
+    }
+
+    fn priority_line(port: u32) -> Option<usize> {
+        if (PIC_PRIORITY_PORT_BASE..PIC_PRIORITY_PORT_BASE + IRQ_LINES_NUM as u32).contains(&port) {
+            Some((port - PIC_PRIORITY_PORT_BASE) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl IoDevice for InterruptController {
+    fn read(&self, port: u32) -> u32 {
+        match port {
+            PIC_PENDING_PORT => self.pending(),
+            PIC_MASK_PORT => self.mask(),
+            p => Self::priority_line(p).map_or(0, |line| self.priorities[line].load(SeqCst)),
+        }
+    }
+
+    fn write(&self, port: u32, value: u32) {
+        match port {
+            PIC_PENDING_PORT => {
+                self.pending.fetch_and(!value, SeqCst);
+            }
+            PIC_MASK_PORT => self.set_mask(value),
+            p => {
+                if let Some(line) = Self::priority_line(p) {
+                    self.set_priority(line, value);
+                }
+            }
+        }
+    }
+}
+
+impl CpuComponent for InterruptController {
+    fn step(&self, bus: Arc<Bus>, cables: &ControlCables) {
+        if cables.load(InterruptAcknowledge) {
+            // the control component only starts an acknowledge cycle for an active
+            // line, a spurious one is sent to the first irq vector
+            let vector = self.acknowledge().unwrap_or(IRQ_VECTOR_BASE);
+            bus.write_from(&MValue::from_u32(vector as u32));
+        }
+        if cables.load(DivideErrorVector) {
+            bus.write_from(&MValue::from_u32(Exception::DivideError.vector() as u32));
+        }
+        if cables.load(InvalidOpcodeVector) {
+            bus.write_from(&MValue::from_u32(Exception::InvalidOpcode.vector() as u32));
+        }
+    }
+
+    fn step_print(&self) {
+        println!(
+            "pic: pending {:#010b} mask {:#010b}",
+            self.pending(),
+            self.mask()
+        );
+    }
+
+    fn writes_bus(&self, cables: &ControlCables) -> bool {
+        cables.load(InterruptAcknowledge)
+            || cables.load(DivideErrorVector)
+            || cables.load(InvalidOpcodeVector)
+    }
+
+    fn reads_bus(&self, _cables: &ControlCables) -> bool {
+        false
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::bits::WORD_MASK;
+use crate::interrupts::InterruptController;
+
+pub const CONSOLE_OUTPUT_PORT: u32 = 1;
+/// read: the next input byte, blocks until one is available
+pub const CONSOLE_INPUT_PORT: u32 = 2;
+/// read: CONSOLE_BYTE_AVAILABLE | CONSOLE_EOF
+pub const CONSOLE_STATUS_PORT: u32 = 3;
+pub const CONSOLE_INPUT_PORTS: Range<u32> = CONSOLE_INPUT_PORT..CONSOLE_STATUS_PORT + 1;
+/// read/write: the exit code of the process running the machine
+pub const EXIT_CODE_PORT: u32 = 4;
+pub const CONSOLE_IRQ_LINE: usize = 1;
+
+pub const CONSOLE_BYTE_AVAILABLE: u32 = 1;
+/// the input has ended, set once every byte has been read
+pub const CONSOLE_EOF: u32 = 2;
+/// read from CONSOLE_INPUT_PORT after the end of the input
+pub const CONSOLE_EOF_VALUE: u32 = WORD_MASK;
+
+/// A peripheral reachable through the `MemoryIsIO` port space. Ports are
+/// passed as absolute port numbers.
+pub trait IoDevice: Send + Sync {
+    /// The value put on the bus by IN.
+    fn read(&self, port: u32) -> u32;
+
+    /// Called by OUT.
+    fn write(&self, port: u32, value: u32);
+
+    /// Called once per control clock cycle.
+    fn tick(&self) {}
+
+    /// Whether the device requests an interrupt, checked after every tick.
+    /// Returning true should also clear the request.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+struct Mapping {
+    ports: Range<u32>,
+    device: Arc<dyn IoDevice>,
+    irq_line: Option<usize>,
+}
+
+/// Dispatches port accesses to the device mapped at the port. Later mappings
+/// shadow earlier ones, reads of unmapped ports return 0 and writes to them
+/// are dropped.
+#[derive(Default)]
+pub struct PortMap {
+    mappings: Vec<Mapping>,
+}
+
+impl PortMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps the device at the port range. Interrupt requests of the device are
+    /// raised on `irq_line` of the interrupt controller.
+    pub fn map(&mut self, ports: Range<u32>, device: Arc<dyn IoDevice>, irq_line: Option<usize>) {
+        self.mappings.push(Mapping {
+            ports,
+            device,
+            irq_line,
+        });
+    }
+
+    pub fn device(&self, port: u32) -> Option<&Arc<dyn IoDevice>> {
+        self.mappings
+            .iter()
+            .rev()
+            .find(|m| m.ports.contains(&port))
+            .map(|m| &m.device)
+    }
+
+    pub fn read(&self, port: u32) -> u32 {
+        match self.device(port) {
+            Some(d) => d.read(port),
+            None => 0,
+        }
+    }
+
+    pub fn write(&self, port: u32, value: u32) {
+        if let Some(d) = self.device(port) {
+            d.write(port, value);
+        }
+    }
+
+    /// Ticks every device and forwards their interrupt requests.
+    pub fn tick(&self, interrupt_controller: &InterruptController) {
+        for m in &self.mappings {
+            m.device.tick();
+            if m.device.irq() {
+                if let Some(line) = m.irq_line {
+                    interrupt_controller.raise(line);
+                }
+            }
+        }
+    }
+}
+
+/// Prints every value written to it as a character on stdout.
+#[derive(Default)]
+pub struct ConsoleOutput {}
+
+impl IoDevice for ConsoleOutput {
+    fn read(&self, _port: u32) -> u32 {
+        0
+    }
+
+    fn write(&self, _port: u32, value: u32) {
+        print!("{}", (value as u8) as char);
+        std::io::stdout().flush().unwrap();
+    }
+}
+
+/// Keeps the last value written to it, 0 until the first write.
+#[derive(Default)]
+pub struct ExitCode {
+    code: AtomicU32,
+}
+
+impl ExitCode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn code(&self) -> u32 {
+        self.code.load(SeqCst)
+    }
+}
+
+impl IoDevice for ExitCode {
+    fn read(&self, _port: u32) -> u32 {
+        self.code()
+    }
+
+    fn write(&self, _port: u32, value: u32) {
+        self.code.store(value, SeqCst);
+    }
+}
+
+/// Keeps every write as a (port, value) pair, used to capture program output.
+#[derive(Default)]
+pub struct OutputRecorder {
+    writes: Mutex<Vec<(u32, u32)>>,
+}
+
+impl OutputRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn writes(&self) -> Vec<(u32, u32)> {
+        self.writes.lock().clone()
+    }
+
+    /// The written values interpreted as characters.
+    pub fn text(&self) -> String {
+        self.writes
+            .lock()
+            .iter()
+            .map(|(_, v)| (*v as u8) as char)
+            .collect()
+    }
+}
+
+impl IoDevice for OutputRecorder {
+    fn read(&self, _port: u32) -> u32 {
+        0
+    }
+
+    fn write(&self, port: u32, value: u32) {
+        self.writes.lock().push((port, value));
+    }
+}
+
+#[derive(Default)]
+struct InputQueue {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Feeds bytes from a reader to CONSOLE_INPUT_PORT and requests an interrupt
+/// whenever new bytes arrive.
+#[derive(Default)]
+pub struct ConsoleInput {
+    queue: Mutex<InputQueue>,
+    arrived: Condvar,
+    irq_request: AtomicBool,
+}
+
+impl ConsoleInput {
+    /// Reads the reader on a background thread, e.g. stdin or a file.
+    pub fn spawn<R: Read + Send + 'static>(mut reader: R) -> Arc<Self> {
+        let ret = Arc::new(Self::default());
+        let input = ret.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => input.push(&buf[..n]),
+                }
+            }
+            input.close();
+        });
+        ret
+    }
+
+    /// An input that ends after the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Arc<Self> {
+        let ret = Arc::new(Self::default());
+        ret.push(bytes);
+        ret.close();
+        ret
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        self.queue.lock().bytes.extend(bytes);
+        self.irq_request.store(true, SeqCst);
+        self.arrived.notify_all();
+    }
+
+    pub fn close(&self) {
+        self.queue.lock().closed = true;
+        self.arrived.notify_all();
+    }
+
+    pub fn status(&self) -> u32 {
+        let queue = self.queue.lock();
+        if !queue.bytes.is_empty() {
+            CONSOLE_BYTE_AVAILABLE
+        } else if queue.closed {
+            CONSOLE_EOF
+        } else {
+            0
+        }
+    }
+}
+
+impl IoDevice for ConsoleInput {
+    fn read(&self, port: u32) -> u32 {
+        if port == CONSOLE_STATUS_PORT {
+            return self.status();
+        }
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(byte) = queue.bytes.pop_front() {
+                return byte as u32;
+            }
+            if queue.closed {
+                return CONSOLE_EOF_VALUE;
+            }
+            self.arrived.wait(&mut queue);
+        }
+    }
+
+    fn write(&self, _port: u32, _value: u32) {}
+
+    fn irq(&self) -> bool {
+        self.irq_request.swap(false, SeqCst)
+    }
+}
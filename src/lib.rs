@@ -4,14 +4,29 @@ pub mod bits;
 pub mod bus;
 pub mod cpu_component;
 pub mod microcodes;
+pub mod dap;
+pub mod debugger;
 pub mod decode;
+pub mod disk;
+pub mod display;
+pub mod gdb;
+pub mod interpreter;
+pub mod interrupts;
+pub mod io;
+pub mod machine;
+pub mod snapshot;
+pub mod timer;
 
 extern crate lazy_static;
 extern crate num;
 #[macro_use]
 extern crate num_derive;
 
-#[cfg(test)]
+// the programs of the tests are written for one word instructions
+#[cfg(all(test, not(feature = "word8")))]
 mod tests;
+#[cfg(all(test, feature = "word8"))]
+mod word8_tests;
 
 use crate::cpu_component::*;
+pub use crate::machine::{Engine, Machine, MachineBuilder, MachineStatus};
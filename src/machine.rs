@@ -0,0 +1,559 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::bits::{words_from_bytes, Flags, MValue, BITNESS, WORD_MASK};
+use crate::bus::{Bus, BusError};
+use crate::cpu_component::{
+    start_cpu_component, AluComponent, Clock, ControlComponent, CpuComponent, CpuComponentArgs,
+    RamComponent, RegisterComponent, WatchHit, Watchpoint, INSTRUCTION_REG_NUM,
+    INTERRUPT_ENABLE_BIT_NUM, INTERRUPT_VECTOR_TABLE, PROGRAM_COUNTER_REG_NUM, RAM_SIZE,
+    REGISTERS_NUM, STACK_POINTER_REG_NUM,
+};
+use crate::decode::dump_cables;
+use crate::interpreter::Interpreter;
+use crate::interrupts::{Exception, InterruptController, PIC_PORTS};
+use crate::io::{ConsoleOutput, ExitCode, IoDevice, PortMap, CONSOLE_OUTPUT_PORT, EXIT_CODE_PORT};
+use crate::microcodes::{create_fetch_microcodes, shift_in_word, INSTRUCTION_WORDS};
+use crate::snapshot::{Snapshot, SNAPSHOT_VERSION};
+use crate::timer::{Timer, TIMER_IRQ_LINE, TIMER_PORTS};
+
+/// How the components are clocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// A thread per component, synchronized through channels.
+    #[default]
+    Threaded,
+    /// Every component on the thread driving the clock, deterministic and
+    /// much faster.
+    Sequential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineStatus {
+    Running,
+    Halted,
+    /// stopped by an exception without a handler, pc is past the instruction
+    /// raising it
+    Faulted(Exception),
+    /// stopped by a microcode step that can't drive the bus
+    BusError(BusError),
+}
+
+/// the cycle timeout of the mmachine binary, a cycle taking longer is stuck,
+/// e.g. waiting on a component that panicked
+pub const DEFAULT_CYCLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn read_image<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u32>> {
+    Ok(words_from_bytes(&std::fs::read(path)?))
+}
+
+type DeviceMapping = (Range<u32>, Arc<dyn IoDevice>, Option<usize>);
+
+pub struct MachineBuilder {
+    image: Vec<u32>,
+    stack_pointer: u32,
+    devices: Vec<DeviceMapping>,
+    engine: Engine,
+    cycle_timeout: Option<Duration>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        MachineBuilder {
+            image: Vec::new(),
+            stack_pointer: RAM_SIZE as u32 - 1,
+            devices: vec![(
+                CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1,
+                Arc::new(ConsoleOutput::default()),
+                None,
+            )],
+            engine: Engine::default(),
+            cycle_timeout: None,
+        }
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the words that will be loaded at 0 at startup
+    pub fn image(mut self, image: &[u32]) -> Self {
+        self.image = image.to_vec();
+        self
+    }
+
+    pub fn stack_pointer(mut self, stack_pointer: u32) -> Self {
+        self.stack_pointer = stack_pointer;
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// How long a cycle may take before the machine stops with a timeout bus
+    /// error. None, the default, waits forever for stuck cycles.
+    pub fn cycle_timeout(mut self, cycle_timeout: Option<Duration>) -> Self {
+        self.cycle_timeout = cycle_timeout;
+        self
+    }
+
+    /// Maps a device at the port range, shadowing the interrupt controller,
+    /// timer, console and previously added devices on the same ports.
+    pub fn device(
+        mut self,
+        ports: Range<u32>,
+        device: Arc<dyn IoDevice>,
+        irq_line: Option<usize>,
+    ) -> Self {
+        self.devices.push((ports, device, irq_line));
+        self
+    }
+
+    /// The interrupt controller, the timer and the exit code, then the added
+    /// devices.
+    fn port_map(&self) -> (PortMap, Arc<InterruptController>, Arc<Timer>, Arc<ExitCode>) {
+        let interrupt_controller = Arc::new(InterruptController::new());
+        let timer = Arc::new(Timer::new());
+        let exit_code = Arc::new(ExitCode::new());
+        let mut ports = PortMap::new();
+        ports.map(PIC_PORTS, interrupt_controller.clone(), None);
+        ports.map(TIMER_PORTS, timer.clone(), Some(TIMER_IRQ_LINE));
+        ports.map(EXIT_CODE_PORT..EXIT_CODE_PORT + 1, exit_code.clone(), None);
+        for (range, device, irq_line) in &self.devices {
+            ports.map(range.clone(), device.clone(), *irq_line);
+        }
+        (ports, interrupt_controller, timer, exit_code)
+    }
+
+    /// Builds an instruction level interpreter with the same image, stack
+    /// pointer and devices instead of the microcoded cpu.
+    pub fn build_interpreter(self) -> Interpreter {
+        let (ports, interrupt_controller, timer, exit_code) = self.port_map();
+        Interpreter::new(
+            &self.image,
+            self.stack_pointer,
+            ports,
+            interrupt_controller,
+            timer,
+            exit_code,
+        )
+    }
+
+    pub fn build(self) -> Machine {
+        let cables = Arc::new(array_init::array_init(|_| AtomicBool::new(false)));
+        let bus = Arc::new(Bus::new());
+        let sent_to_alu = Arc::new(AtomicUsize::new(0));
+        let (alu_tx, alu_rx) = channel();
+        let alu_tx_arc = Arc::new(Mutex::new(alu_tx));
+
+        let (ports, interrupt_controller, timer, exit_code) = self.port_map();
+        let ports = Arc::new(ports);
+
+        let mut memory = Vec::with_capacity(RAM_SIZE);
+        memory.resize_with(RAM_SIZE, MValue::default);
+        let ram = Arc::new(RamComponent {
+            memory: memory.into_boxed_slice().try_into().unwrap(),
+            memory_address_register: MValue::default(),
+            ram_register: MValue::default(),
+            ports: ports.clone(),
+            watchpoints: Mutex::new(Vec::new()),
+            watch_hits: Mutex::new(Vec::new()),
+        });
+        let mut registers = Vec::new();
+        for i in 0..REGISTERS_NUM {
+            let mut start_value: u32 = 0;
+            if i == STACK_POINTER_REG_NUM {
+                start_value = self.stack_pointer;
+            }
+            registers.push(Arc::new(RegisterComponent {
+                value: MValue::from_u32(start_value),
+                reg_num: i,
+                alu_tx: alu_tx_arc.clone(),
+                sent_to_alu: sent_to_alu.clone(),
+            }));
+        }
+        let flags_register = Arc::new(Flags::from_u32(1 << INTERRUPT_ENABLE_BIT_NUM));
+        let alu = Arc::new(AluComponent {
+            reg_a: MValue::from_u32(0),
+            reg_b: MValue::from_u32(0),
+            remainder: MValue::from_u32(0),
+            flags_reg: flags_register.clone(),
+        });
+        alu.update_flags();
+
+        let mut components: Vec<Arc<dyn CpuComponent + Send + Sync>> = vec![ram.clone()];
+        for r in &registers {
+            components.push(r.clone());
+        }
+        components.push(alu.clone());
+        components.push(interrupt_controller.clone());
+
+        let mut threads = Vec::new();
+        let clock = match self.engine {
+            Engine::Threaded => {
+                let finished = Arc::new(AtomicUsize::new(0));
+                let (clock_tx, clock_rx) = channel();
+                let (alu_clock_tx, alu_clock_rx) = channel();
+                let (ctrl_tx, ctrl_rx) = channel();
+                let alu_thread = alu.clone();
+                threads.push(std::thread::spawn(move || {
+                    alu_thread.run(alu_rx, alu_clock_tx, ctrl_tx);
+                }));
+                let mut txs = Vec::new();
+                for c in components.clone() {
+                    let (tx, rx) = channel();
+                    threads.push(start_cpu_component(
+                        CpuComponentArgs {
+                            cables: cables.clone(),
+                            bus: bus.clone(),
+                            rx,
+                            finished: finished.clone(),
+                            clock_tx: clock_tx.clone(),
+                        },
+                        c,
+                    ));
+                    txs.push(tx);
+                }
+                Clock::Threaded {
+                    clock_rx,
+                    txs,
+                    finished,
+                    alu_clock_rx,
+                    sent_to_alu,
+                    ctrl_rx,
+                }
+            }
+            Engine::Sequential => Clock::Sequential {
+                alu: alu.clone(),
+                alu_rx,
+                sent_to_alu,
+            },
+        };
+        let control = ControlComponent {
+            clock,
+            components,
+            cables,
+            bus,
+            microcode_counter: AtomicUsize::new(0),
+            instruction_register: AtomicU32::new(0),
+            current_microcodes: Arc::new(Mutex::new(create_fetch_microcodes())),
+            flags_register,
+            interrupt_controller,
+            ports,
+            cycle_timeout: self.cycle_timeout,
+        };
+
+        let machine = Machine {
+            ram,
+            registers,
+            alu,
+            control,
+            timer,
+            exit_code,
+            status: MachineStatus::Running,
+            report: None,
+            threads,
+        };
+        machine.load_image(&self.image);
+        machine
+    }
+}
+
+/// A fully wired cpu. With the threaded engine every component runs in its own
+/// thread, the clock is driven by the thread calling the `step_*` and `run`
+/// methods.
+pub struct Machine {
+    ram: Arc<RamComponent>,
+    registers: Vec<Arc<RegisterComponent>>,
+    alu: Arc<AluComponent>,
+    control: ControlComponent,
+    timer: Arc<Timer>,
+    exit_code: Arc<ExitCode>,
+    status: MachineStatus,
+    report: Option<String>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    /// Copies the image into memory starting at address 0.
+    pub fn load_image(&self, image: &[u32]) {
+        for (i, word) in image.iter().enumerate() {
+            self.write_ram(i as u32, *word);
+        }
+    }
+
+    /// Runs a single clock cycle (one microcode step).
+    pub fn step_cycle(&mut self) -> MachineStatus {
+        if self.status != MachineStatus::Running {
+            return self.status;
+        }
+        if let Some(exception) = self.control.next_exception() {
+            // a vector of 0 means no handler is installed
+            if self.read_ram((INTERRUPT_VECTOR_TABLE + exception.vector()) as u32) == 0 {
+                self.status = MachineStatus::Faulted(exception);
+                return self.status;
+            }
+        }
+        match self.control.tick() {
+            Ok(false) => {}
+            Ok(true) => self.status = MachineStatus::Halted,
+            Err(e) => {
+                self.status = MachineStatus::BusError(e);
+                self.report = Some(self.control.report());
+            }
+        }
+        self.status
+    }
+
+    /// Runs clock cycles until the current instruction has finished, including
+    /// the fetch of the next one.
+    pub fn step_instruction(&mut self) -> MachineStatus {
+        while self.step_cycle() == MachineStatus::Running {
+            if self.control.at_instruction_boundary() {
+                break;
+            }
+        }
+        self.status
+    }
+
+    /// Runs until the cpu halts or faults.
+    pub fn run(&mut self) -> MachineStatus {
+        while self.step_cycle() == MachineStatus::Running {}
+        self.status
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        self.status
+    }
+
+    /// What the cpu was doing when a bus error stopped it.
+    pub fn report(&self) -> Option<&str> {
+        self.report.as_deref()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.status == MachineStatus::Halted
+    }
+
+    pub fn register(&self, reg_num: usize) -> u32 {
+        self.registers[reg_num].value.as_u32()
+    }
+
+    /// Overwrites a register, keeping the alu and control copies in sync.
+    pub fn set_register(&self, reg_num: usize, value: u32) {
+        let mvalue = MValue::from_u32(value);
+        self.registers[reg_num].value.set(&mvalue);
+        if reg_num == 0 {
+            self.alu.reg_a.set(&mvalue);
+        }
+        if reg_num == 1 {
+            self.alu.reg_b.set(&mvalue);
+        }
+        if reg_num < 2 {
+            self.alu.update_flags();
+        }
+        if reg_num == INSTRUCTION_REG_NUM {
+            self.control.latch_instruction_word(value);
+        }
+    }
+
+    /// Devices and the host raise interrupt lines through the controller.
+    pub fn interrupt_controller(&self) -> &Arc<InterruptController> {
+        &self.control.interrupt_controller
+    }
+
+    pub fn timer(&self) -> &Arc<Timer> {
+        &self.timer
+    }
+
+    /// The last value written to EXIT_CODE_PORT.
+    pub fn exit_code(&self) -> u32 {
+        self.exit_code.code()
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.control.flags_register.as_u32()
+    }
+
+    pub fn set_flags(&self, value: u32) {
+        self.control.flags_register.set_u32(value);
+    }
+
+    /// The address of the instruction about to run, the cpu fetches ahead so
+    /// between instructions it is at pc - INSTRUCTION_WORDS.
+    pub fn next_address(&self) -> u32 {
+        self.register(PROGRAM_COUNTER_REG_NUM).wrapping_sub(INSTRUCTION_WORDS as u32) & WORD_MASK
+    }
+
+    /// Fetches the instruction at address, so it runs next.
+    pub fn jump(&self, address: u32) {
+        for i in 0..INSTRUCTION_WORDS as u32 {
+            self.set_register(INSTRUCTION_REG_NUM, self.read_ram(address.wrapping_add(i)));
+        }
+        self.set_register(
+            PROGRAM_COUNTER_REG_NUM,
+            address.wrapping_add(INSTRUCTION_WORDS as u32),
+        );
+    }
+
+    /// The whole instruction latched and decoded by the control unit, between
+    /// instructions the one at next_address. Unlike the inst register, which
+    /// only holds the last fetched word, it has every word of the instruction.
+    pub fn instruction(&self) -> u32 {
+        self.control.instruction_register.load(SeqCst)
+    }
+
+    /// The instruction stored at address.
+    pub fn read_instruction(&self, address: u32) -> u32 {
+        (0..INSTRUCTION_WORDS as u32).fold(0, |instruction, i| {
+            shift_in_word(instruction, self.read_ram(address.wrapping_add(i)))
+        })
+    }
+
+    pub fn read_ram(&self, address: u32) -> u32 {
+        self.ram.memory[address as usize % RAM_SIZE].as_u32()
+    }
+
+    pub fn write_ram(&self, address: u32, value: u32) {
+        self.ram.memory[address as usize % RAM_SIZE].set_u32(value);
+    }
+
+    /// Replaces the ram address ranges whose accesses are recorded.
+    pub fn set_watchpoints(&self, watchpoints: Vec<Watchpoint>) {
+        *self.ram.watchpoints.lock() = watchpoints;
+    }
+
+    /// The watched accesses since the last call.
+    pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+        self.ram.take_watch_hits()
+    }
+
+    /// Whether the last cycle asserted the cable, numbered like the cables of
+    /// `ControlCables`.
+    pub fn cable_asserted(&self, cable: usize) -> bool {
+        self.control.cables[cable].load(SeqCst)
+    }
+
+    /// Whether the last instruction has finished, including the fetch of the
+    /// next one.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.control.at_instruction_boundary()
+    }
+
+    pub fn microcode_counter(&self) -> usize {
+        self.control.microcode_counter.load(SeqCst)
+    }
+
+    /// The cables asserted in the last cycle.
+    pub fn cables(&self) -> String {
+        dump_cables(&self.control.cables).trim_end().to_string()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let ram = self
+            .ram
+            .memory
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| word.as_u32() != 0)
+            .map(|(address, word)| (address as u32, word.as_u32()))
+            .collect();
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            bitness: BITNESS,
+            ram,
+            memory_address_register: self.ram.memory_address_register.as_u32(),
+            ram_register: self.ram.ram_register.as_u32(),
+            registers: (0..REGISTERS_NUM).map(|r| self.register(r)).collect(),
+            alu_a: self.alu.reg_a.as_u32(),
+            alu_b: self.alu.reg_b.as_u32(),
+            alu_remainder: self.alu.remainder.as_u32(),
+            flags: self.flags(),
+            instruction: self.instruction(),
+            microcode_counter: self.microcode_counter(),
+            microcodes: self.control.current_microcodes.lock().clone(),
+        }
+    }
+
+    /// Puts the machine in the state of a snapshot, the machine keeps running
+    /// from where the snapshot was taken.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        snapshot.check()?;
+        for (address, word) in self.ram.memory.iter().enumerate() {
+            word.set_u32(snapshot.ram.get(&(address as u32)).copied().unwrap_or_default());
+        }
+        self.ram.memory_address_register.set_u32(snapshot.memory_address_register);
+        self.ram.ram_register.set_u32(snapshot.ram_register);
+        for (reg_num, value) in snapshot.registers.iter().enumerate() {
+            self.set_register(reg_num, *value);
+        }
+        self.alu.reg_a.set_u32(snapshot.alu_a);
+        self.alu.reg_b.set_u32(snapshot.alu_b);
+        self.alu.remainder.set_u32(snapshot.alu_remainder);
+        self.set_flags(snapshot.flags);
+        self.control.instruction_register.store(snapshot.instruction, SeqCst);
+        self.control.microcode_counter.store(snapshot.microcode_counter, SeqCst);
+        *self.control.current_microcodes.lock() = snapshot.microcodes.clone();
+        self.status = MachineStatus::Running;
+        self.report = None;
+        Ok(())
+    }
+
+    pub fn step_print(&self) {
+        self.ram.step_print();
+        for r in &self.registers {
+            r.step_print();
+        }
+        self.alu.step_print();
+        self.control.interrupt_controller.step_print();
+        self.control.step_print();
+    }
+}
+
+/// Closing the clock channels and the bus ends the component threads, the alu
+/// thread ends once they have dropped the registers. After a timed out cycle a
+/// component may still be inside a device call, it gets one more cycle timeout
+/// to return and otherwise ends on its own once the call returns.
+impl Drop for Machine {
+    fn drop(&mut self) {
+        if let Clock::Threaded { txs, .. } = &mut self.control.clock {
+            txs.clear();
+        }
+        self.control.bus.close();
+        self.control.components.clear();
+        self.registers.clear();
+        let timed_out = self.status == MachineStatus::BusError(BusError::Timeout);
+        if timed_out {
+            let deadline = Instant::now() + self.control.cycle_timeout.unwrap_or_default();
+            while self.threads.iter().any(|t| !t.is_finished()) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        // a disconnected machine lost a component thread that panicked
+        let disconnected = self.status == MachineStatus::BusError(BusError::Disconnected);
+        for thread in self.threads.drain(..) {
+            if !timed_out || thread.is_finished() {
+                let result = thread.join();
+                if !disconnected {
+                    result.unwrap();
+                }
+            }
+        }
+    }
+}
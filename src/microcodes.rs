@@ -1,12 +1,16 @@
 use std::sync::atomic::Ordering::SeqCst;
 use std::vec;
 
-use crate::bits::MValue;
+use crate::bits::{Flags, BITNESS, WORD_MASK};
 use crate::cpu_component::{
-    reg_dec, reg_in, reg_inc, reg_out, EQUAL_BIT_NUM, GREATER_BIT_NUM, INSTRUCTION_REG_NUM,
-    PROGRAM_COUNTER_REG_NUM, STACK_POINTER_REG_NUM,
+    alu_select, reg_dec, reg_in, reg_inc, reg_out, CARRY_BIT_NUM, EQUAL_BIT_NUM, GREATER_BIT_NUM,
+    INSTRUCTION_REG_NUM, INTERRUPT_ENABLE_BIT_NUM, OVERFLOW_BIT_NUM, PROGRAM_COUNTER_REG_NUM,
+    REGISTERS_NUM, SIGNED_GREATER_BIT_NUM, SIGN_BIT_NUM, STACK_POINTER_REG_NUM, ZERO_BIT_NUM,
+    ZERO_DIVISOR_BIT_NUM,
 };
+use crate::interrupts::Exception;
 
+use crate::cpu_component::AluOp;
 use crate::cpu_component::ControlCable::*;
 
 pub type Microcodes = Vec<Vec<usize>>;
@@ -37,6 +41,29 @@ pub enum INSTRUCTION {
     LOAD = 0b010110,
     STORE = 0b010111,
     LDCNST = 0b011000,
+    STI = 0b011001,
+    CLI = 0b011010,
+    JC = 0b011011,
+    JO = 0b011100,
+    JZ = 0b011101,
+    JS = 0b011110,
+    IMUL = 0b011111,
+    IDIV = 0b100000,
+    JGS = 0b100001,
+    JGES = 0b100010,
+    JLS = 0b100011,
+    JLES = 0b100100,
+    AND = 0b100101,
+    OR = 0b100110,
+    XOR = 0b100111,
+    NOT = 0b101000,
+    SHL = 0b101001,
+    SHR = 0b101010,
+    SAR = 0b101011,
+    ROL = 0b101100,
+    ROR = 0b101101,
+    MOD = 0b101110,
+    DIVMOD = 0b101111,
 }
 
 #[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, FromPrimitive)]
@@ -53,42 +80,132 @@ pub enum REG {
 
 use INSTRUCTION::*;
 
-pub const OPCODE_SHIFT: u8 = 10;
-pub const SOURCE_SHIFT: u8 = 5;
-pub const OPCODE_MASK: u32 = 0b1111110000000000;
-pub const SOURCE_MASK: u32 = 0b0000001111100000;
-pub const DEST_MASK: u32 = 0b0000000000011111;
+/// words taken by an instruction, words narrower than 16 bits split it into
+/// several, the highest bits first
+pub const INSTRUCTION_WORDS: usize = if BITNESS < 16 { 16 / BITNESS } else { 1 };
+pub const INSTRUCTION_BITS: usize = BITNESS * INSTRUCTION_WORDS;
+pub const INSTRUCTION_MASK: u32 = u32::MAX >> (32 - INSTRUCTION_BITS);
+
+// the opcode takes the 6 highest bits of an instruction, followed by the 5 bit
+// source and destination, wider words leave the lowest bits unused
+pub const OPCODE_SHIFT: u8 = INSTRUCTION_BITS as u8 - 6;
+pub const SOURCE_SHIFT: u8 = OPCODE_SHIFT - 5;
+pub const DEST_SHIFT: u8 = SOURCE_SHIFT - 5;
+pub const OPCODE_MASK: u32 = 0b111111 << OPCODE_SHIFT;
+pub const SOURCE_MASK: u32 = 0b11111 << SOURCE_SHIFT;
+pub const DEST_MASK: u32 = 0b11111 << DEST_SHIFT;
+
+/// The words an instruction is stored as.
+pub fn instruction_words(instruction: u32) -> [u32; INSTRUCTION_WORDS] {
+    array_init::array_init(|i| {
+        let shift = BITNESS * (INSTRUCTION_WORDS - 1 - i);
+        (instruction as u64 >> shift) as u32 & WORD_MASK
+    })
+}
+
+/// Appends the next word of an instruction, what the control unit does with
+/// every word loaded into the instruction register.
+pub fn shift_in_word(instruction: u32, word: u32) -> u32 {
+    ((instruction as u64) << BITNESS | (word & WORD_MASK) as u64) as u32 & INSTRUCTION_MASK
+}
 
 pub fn create_fetch_microcodes() -> Microcodes {
-    vec![
-        vec![reg_out(PROGRAM_COUNTER_REG_NUM), MemoryAddressIn as usize],
-        vec![
+    let mut ret = vec![];
+    for _ in 0..INSTRUCTION_WORDS {
+        ret.push(vec![reg_out(PROGRAM_COUNTER_REG_NUM), MemoryAddressIn as usize]);
+        ret.push(vec![
             RamOut as usize,
             reg_in(INSTRUCTION_REG_NUM),
             reg_inc(PROGRAM_COUNTER_REG_NUM),
+        ]);
+    }
+    ret
+}
+
+/// Pushes pc to the stack, disables interrupts and jumps to the vector of the
+/// interrupt number put on the bus by `vector_out`.
+pub fn create_interrupt_microcodes(vector_out: usize) -> Microcodes {
+    vec![
+        vec![
+            reg_out(STACK_POINTER_REG_NUM),
+            MemoryAddressIn as usize,
         ],
+        vec![
+            reg_out(PROGRAM_COUNTER_REG_NUM),
+            RamIn as usize,
+            reg_dec(STACK_POINTER_REG_NUM),
+            InterruptDisable as usize,
+        ],
+        vec![vector_out, Interrupt as usize, MemoryAddressIn as usize],
+        vec![RamOut as usize, reg_in(PROGRAM_COUNTER_REG_NUM)],
     ]
 }
 
-pub fn create_microcodes(instruction: u32, flags_reg: &MValue) -> Microcodes {
+/// Serves the active line of the interrupt controller. Runs in place of an
+/// already fetched instruction, so pc is moved back to it first.
+pub fn create_irq_microcodes() -> Microcodes {
+    let mut ret = vec![vec![reg_dec(PROGRAM_COUNTER_REG_NUM)]; INSTRUCTION_WORDS];
+    ret.append(&mut create_interrupt_microcodes(InterruptAcknowledge as usize));
+    ret.append(&mut create_fetch_microcodes());
+    ret
+}
+
+/// Runs the handler of the exception like INT, regardless of the interrupt
+/// enable flag. The pushed return address is the instruction after the one
+/// raising it.
+pub fn create_exception_microcodes(exception: Exception) -> Microcodes {
+    let vector_out = match exception {
+        Exception::DivideError => DivideErrorVector,
+        Exception::InvalidOpcode => InvalidOpcodeVector,
+    };
+    create_interrupt_microcodes(vector_out as usize)
+}
+
+/// The exception raised by the instruction instead of running it.
+pub fn instruction_exception(instruction: u32, flags_reg: &Flags) -> Option<Exception> {
+    let opcode = (instruction & OPCODE_MASK) >> OPCODE_SHIFT;
+    let src: usize = ((instruction & SOURCE_MASK) >> SOURCE_SHIFT) as usize;
+    let dst: usize = ((instruction & DEST_MASK) >> DEST_SHIFT) as usize;
+    let opcode: Option<INSTRUCTION> = num::FromPrimitive::from_u32(opcode);
+    match opcode {
+        None => Some(Exception::InvalidOpcode),
+        Some(_) if src >= REGISTERS_NUM || dst >= REGISTERS_NUM => Some(Exception::InvalidOpcode),
+        Some(DIV | IDIV | MOD | DIVMOD) if flags_reg.bit(ZERO_DIVISOR_BIT_NUM).load(SeqCst) => {
+            Some(Exception::DivideError)
+        }
+        _ => None,
+    }
+}
+
+/// Puts the result of the alu operation into dst.
+fn alu_step(op: AluOp, dst: usize) -> Vec<usize> {
+    let mut ret = alu_select(op);
+    ret.push(AluOut as usize);
+    ret.push(reg_in(dst));
+    ret
+}
+
+pub fn create_microcodes(instruction: u32, flags_reg: &Flags) -> Microcodes {
     let opcode = (instruction & OPCODE_MASK) >> OPCODE_SHIFT;
     let src: usize = ((instruction & SOURCE_MASK) >> SOURCE_SHIFT) as usize;
-    let dst: usize = (instruction & DEST_MASK) as usize;
+    let dst: usize = ((instruction & DEST_MASK) >> DEST_SHIFT) as usize;
 
     let mut ret: Microcodes = vec![];
 
+    if let Some(exception) = instruction_exception(instruction, flags_reg) {
+        ret.append(&mut create_exception_microcodes(exception));
+        ret.append(&mut create_fetch_microcodes());
+        return ret;
+    }
+
+    // valid, the exception check decodes it first
     match num::FromPrimitive::from_u32(opcode).unwrap() {
         HLT => ret.push(vec![Halt as usize]),
         MOV => ret.push(vec![reg_out(src), reg_in(dst)]),
-        ADD => ret.push(vec![AluOut as usize, reg_in(dst)]),
-        SUB => ret.push(vec![SubDiv as usize, AluOut as usize, reg_in(dst)]),
-        MUL => ret.push(vec![AddMul as usize, AluOut as usize, reg_in(dst)]),
-        DIV => ret.push(vec![
-            AddMul as usize,
-            SubDiv as usize,
-            AluOut as usize,
-            reg_in(dst),
-        ]),
+        ADD => ret.push(alu_step(AluOp::Add, dst)),
+        SUB => ret.push(alu_step(AluOp::Sub, dst)),
+        MUL => ret.push(alu_step(AluOp::Mul, dst)),
+        DIV => ret.push(alu_step(AluOp::Div, dst)),
         CALL => {
             ret.push(vec![
                 reg_out(STACK_POINTER_REG_NUM),
@@ -170,8 +287,23 @@ pub fn create_microcodes(instruction: u32, flags_reg: &MValue) -> Microcodes {
             ]);
             ret.push(vec![MemoryIsIO as usize, reg_in(dst), RamOut as usize]);
         }
-        INT => todo!(),
-        EOI => todo!(),
+        INT => {
+            if flags_reg.bit(INTERRUPT_ENABLE_BIT_NUM).load(SeqCst) {
+                ret.append(&mut create_interrupt_microcodes(reg_out(src)));
+            }
+        }
+        EOI => {
+            ret.push(vec![reg_inc(STACK_POINTER_REG_NUM)]);
+            ret.push(vec![
+                reg_out(STACK_POINTER_REG_NUM),
+                MemoryAddressIn as usize,
+            ]);
+            ret.push(vec![
+                reg_in(PROGRAM_COUNTER_REG_NUM),
+                RamOut as usize,
+                InterruptEnable as usize,
+            ]);
+        }
         INC => ret.push(vec![reg_inc(dst)]),
         DEC => ret.push(vec![reg_dec(dst)]),
         LOAD => {
@@ -197,6 +329,69 @@ pub fn create_microcodes(instruction: u32, flags_reg: &MValue) -> Microcodes {
                 ]);
             }
         }
+        STI => ret.push(vec![InterruptEnable as usize]),
+        CLI => ret.push(vec![InterruptDisable as usize]),
+        JC => {
+            if flags_reg.bit(CARRY_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JO => {
+            if flags_reg.bit(OVERFLOW_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JZ => {
+            if flags_reg.bit(ZERO_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JS => {
+            if flags_reg.bit(SIGN_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        IMUL => ret.push(alu_step(AluOp::Imul, dst)),
+        IDIV => ret.push(alu_step(AluOp::Idiv, dst)),
+        JGS => {
+            if flags_reg.bit(SIGNED_GREATER_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JGES => {
+            if flags_reg.bit(SIGNED_GREATER_BIT_NUM).load(SeqCst)
+                || flags_reg.bit(EQUAL_BIT_NUM).load(SeqCst)
+            {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JLS => {
+            if !flags_reg.bit(SIGNED_GREATER_BIT_NUM).load(SeqCst)
+                && !flags_reg.bit(EQUAL_BIT_NUM).load(SeqCst)
+            {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        JLES => {
+            if !flags_reg.bit(SIGNED_GREATER_BIT_NUM).load(SeqCst) {
+                ret.push(vec![reg_out(dst), reg_in(PROGRAM_COUNTER_REG_NUM)]);
+            }
+        }
+        AND => ret.push(alu_step(AluOp::And, dst)),
+        OR => ret.push(alu_step(AluOp::Or, dst)),
+        XOR => ret.push(alu_step(AluOp::Xor, dst)),
+        NOT => ret.push(alu_step(AluOp::Not, dst)),
+        SHL => ret.push(alu_step(AluOp::Shl, dst)),
+        SHR => ret.push(alu_step(AluOp::Shr, dst)),
+        SAR => ret.push(alu_step(AluOp::Sar, dst)),
+        ROL => ret.push(alu_step(AluOp::Rol, dst)),
+        ROR => ret.push(alu_step(AluOp::Ror, dst)),
+        MOD => ret.push(alu_step(AluOp::Mod, dst)),
+        DIVMOD => {
+            // dst may be a or b, so the remainder is kept by the alu
+            ret.push(alu_step(AluOp::Div, dst));
+            ret.push(alu_step(AluOp::Remainder, src));
+        }
     }
     ret.append(&mut create_fetch_microcodes());
     ret
@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bits::BITNESS;
+use crate::cpu_component::{CONTROL_CABLES_SIZE, RAM_SIZE, REGISTERS_NUM};
+use crate::decode::register_name;
+use crate::microcodes::Microcodes;
+
+/// the format of the snapshots this build saves, bumped when a field changes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The state of the cpu and memory of a machine, saved as json. Devices and
+/// the interrupt controller aren't included.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// the format it was saved in, 0 for snapshots from before versioning
+    #[serde(default)]
+    pub version: u32,
+    /// the word width of the machine that took it
+    pub bitness: usize,
+    /// the words of the memory that aren't 0, by address
+    pub ram: BTreeMap<u32, u32>,
+    pub memory_address_register: u32,
+    pub ram_register: u32,
+    pub registers: Vec<u32>,
+    pub alu_a: u32,
+    pub alu_b: u32,
+    pub alu_remainder: u32,
+    pub flags: u32,
+    /// the instruction decoded by the control unit
+    pub instruction: u32,
+    pub microcode_counter: usize,
+    /// the microcodes of the instruction being run
+    pub microcodes: Microcodes,
+}
+
+impl Snapshot {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Whether a machine of this build can restore it.
+    pub fn check(&self) -> Result<(), String> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "the snapshot has format version {}, the machine reads version {}",
+                self.version, SNAPSHOT_VERSION
+            ));
+        }
+        if self.bitness != BITNESS {
+            return Err(format!(
+                "the snapshot has {} bit words, the machine {} bit ones",
+                self.bitness, BITNESS
+            ));
+        }
+        if self.registers.len() != REGISTERS_NUM {
+            return Err(format!(
+                "the snapshot has {} registers",
+                self.registers.len()
+            ));
+        }
+        if let Some(address) = self.ram.keys().find(|a| **a as usize >= RAM_SIZE) {
+            return Err(format!(
+                "the snapshot has ram address {} past the ram",
+                address
+            ));
+        }
+        if self.microcode_counter > self.microcodes.len() {
+            return Err(format!(
+                "the snapshot is at microcode step {} of {}",
+                self.microcode_counter,
+                self.microcodes.len()
+            ));
+        }
+        if let Some(cable) = self
+            .microcodes
+            .iter()
+            .flatten()
+            .find(|c| **c >= CONTROL_CABLES_SIZE)
+        {
+            return Err(format!("the snapshot has unknown control cable {}", cable));
+        }
+        Ok(())
+    }
+
+    /// A line for every difference from other, as `name: this -> other`.
+    pub fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let mut ret = Vec::new();
+        let mut compare = |name: &str, a: u32, b: u32| {
+            if a != b {
+                ret.push(format!("{}: {} -> {}", name, a, b));
+            }
+        };
+        compare("version", self.version, other.version);
+        compare("bitness", self.bitness as u32, other.bitness as u32);
+        compare(
+            "registers",
+            self.registers.len() as u32,
+            other.registers.len() as u32,
+        );
+        for (i, (a, b)) in self.registers.iter().zip(&other.registers).enumerate() {
+            compare(register_name(i).unwrap_or("register"), *a, *b);
+        }
+        compare("flags", self.flags, other.flags);
+        compare("instruction", self.instruction, other.instruction);
+        compare("alu a", self.alu_a, other.alu_a);
+        compare("alu b", self.alu_b, other.alu_b);
+        compare("alu remainder", self.alu_remainder, other.alu_remainder);
+        compare(
+            "mar",
+            self.memory_address_register,
+            other.memory_address_register,
+        );
+        compare("ram register", self.ram_register, other.ram_register);
+        compare(
+            "microcode counter",
+            self.microcode_counter as u32,
+            other.microcode_counter as u32,
+        );
+        let addresses: BTreeSet<&u32> = self.ram.keys().chain(other.ram.keys()).collect();
+        for address in addresses {
+            let a = self.ram.get(address).copied().unwrap_or_default();
+            let b = other.ram.get(address).copied().unwrap_or_default();
+            compare(&format!("ram {}", address), a, b);
+        }
+        if self.microcodes != other.microcodes {
+            ret.push("microcodes differ".to_string());
+        }
+        ret
+    }
+}
@@ -1,16 +1,61 @@
-use crate::bits::MValue;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use crate::bits::{words_from_bytes, words_to_bytes, MValue, BITNESS, SIGN_MASK, WORD_BYTES};
+use crate::bus::{Bus, BusError};
+use crate::cpu_component::{
+    bus_writer, reg_in, reg_out, ControlCables, CpuComponent, RegisterComponent, CARRY_BIT_NUM,
+    CONTROL_CABLES_SIZE, EQUAL_BIT_NUM, INTERRUPT_ENABLE_BIT_NUM, INTERRUPT_VECTOR_TABLE,
+    PROGRAM_COUNTER_REG_NUM, RAM_SIZE, REGISTERS_NUM, STACK_POINTER_REG_NUM,
+};
+use crate::dap::DapServer;
+use crate::debugger::{Debugger, Symbols};
+use crate::decode::decode_instruction;
+use crate::disk::{
+    BlockDevice, DISK_COMMAND_PORT, DISK_DATA_PORT, DISK_ERROR, DISK_INDEX_PORT, DISK_IRQ_LINE,
+    DISK_PORTS, DISK_READ, DISK_SECTOR_PORT, DISK_SIZE_PORT, DISK_STATUS_PORT, DISK_WRITE,
+    SECTOR_WORDS,
+};
+use crate::display::{
+    TextDisplay, DISPLAY_CLEAR, DISPLAY_CONTROL_PORT, DISPLAY_CURSOR_PORT, DISPLAY_DATA_PORT,
+    DISPLAY_PORTS, DISPLAY_WIDTH_PORT,
+};
+use crate::gdb::GdbStub;
+use crate::interrupts::{
+    Exception, IRQ_VECTOR_BASE, PIC_MASK_PORT, PIC_PENDING_PORT, PIC_PRIORITY_PORT_BASE,
+};
+use crate::io::{
+    ConsoleInput, IoDevice, OutputRecorder, CONSOLE_BYTE_AVAILABLE, CONSOLE_EOF, CONSOLE_EOF_VALUE,
+    CONSOLE_INPUT_PORT, CONSOLE_INPUT_PORTS, CONSOLE_IRQ_LINE, CONSOLE_OUTPUT_PORT,
+    CONSOLE_STATUS_PORT, EXIT_CODE_PORT,
+};
+use crate::microcodes::INSTRUCTION::*;
+use crate::microcodes::{DEST_SHIFT, INSTRUCTION, OPCODE_SHIFT, REG, SOURCE_SHIFT};
+use crate::snapshot::Snapshot;
+use crate::timer::{
+    TIMER_CONTROL_PORT, TIMER_ENABLE, TIMER_EXPIRED, TIMER_IRQ_LINE, TIMER_PERIODIC,
+    TIMER_RELOAD_PORT, TIMER_STATUS_PORT,
+};
+use crate::{Engine, Machine, MachineBuilder, MachineStatus};
 
 #[test]
 fn mvalue_test() {
     let v = MValue::from_u32(1);
-    assert_eq!(v.as_string(), "0000000000000001");
+    assert_eq!(v.as_string(), format!("{:0>1$}", "1", BITNESS));
     assert_eq!(v.as_u32(), 1);
 }
 
 #[test]
 fn mvalue_test_2() {
     let v = MValue::from_u32(11111);
-    assert_eq!(v.as_string(), "0010101101100111");
+    assert_eq!(v.as_string(), format!("{:0>1$}", "10101101100111", BITNESS));
     assert_eq!(v.as_u32(), 11111);
 }
 
@@ -46,4 +91,1355 @@ fn mvalue_test_mul() {
     let v2 = MValue::from_u32(42);
     v.mul(&v2);
     assert_eq!(v.as_u32(), 2898);
-}
\ No newline at end of file
+}
+
+#[test]
+fn mvalue_bit_test() {
+    let v = MValue::from_u32(0b1010);
+    assert!(v.bit(1).load(SeqCst));
+    assert!(!v.bit(2).load(SeqCst));
+    v.bit(2).store(true, SeqCst);
+    v.bit(3).store(false, SeqCst);
+    assert_eq!(v.as_u32(), 0b0110);
+    let v = MValue::from_u32(0);
+    v.sub(&MValue::from_u32(1));
+    assert_eq!(v.as_u32(), crate::bits::WORD_MASK);
+}
+
+#[test]
+fn mvalue_zero_divisor_test() {
+    let zero = MValue::from_u32(0);
+    let v = MValue::from_u32(7);
+    v.div(&zero);
+    assert_eq!(v.as_u32(), 0);
+    let v = MValue::from_u32(7);
+    v.rem(&zero);
+    assert_eq!(v.as_u32(), 7);
+    let v = MValue::from_u32(SIGN_MASK);
+    assert!(!v.idiv(&zero));
+    assert_eq!(v.as_u32(), 0);
+}
+
+fn encode(op: INSTRUCTION, src: REG, dst: REG) -> u32 {
+    (op as u32) << OPCODE_SHIFT | (src as u32) << SOURCE_SHIFT | (dst as u32) << DEST_SHIFT
+}
+
+fn ldcnst(dst: REG, value: u32) -> [u32; 2] {
+    [encode(LDCNST, REG::A, dst), value]
+}
+
+fn build_machine(program: &[&[u32]]) -> Machine {
+    MachineBuilder::new().image(&program.concat()).build()
+}
+
+#[test]
+fn machine_run_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, 5),
+        &ldcnst(REG::B, 7),
+        &[encode(ADD, REG::A, REG::C)],
+        &[encode(MUL, REG::A, REG::D)],
+        &[encode(HLT, REG::A, REG::A)],
+    ]);
+    assert_eq!(m.run(), MachineStatus::Halted);
+    assert!(m.is_halted());
+    assert_eq!(m.register(REG::C as usize), 12);
+    assert_eq!(m.register(REG::D as usize), 35);
+    assert_eq!(m.register(STACK_POINTER_REG_NUM), RAM_SIZE as u32 - 1);
+}
+
+#[test]
+fn machine_step_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::C, 3),
+        &[encode(INC, REG::A, REG::C)],
+        &[encode(HLT, REG::A, REG::A)],
+    ]);
+    // the first instruction only fetches
+    assert_eq!(m.step_instruction(), MachineStatus::Running);
+    assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 1);
+    m.step_instruction();
+    assert_eq!(m.register(REG::C as usize), 3);
+    assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 3);
+    m.step_cycle();
+    assert_eq!(m.register(REG::C as usize), 4);
+    assert_eq!(m.microcode_counter(), 1);
+    m.step_instruction();
+    assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 4);
+    assert_eq!(m.run(), MachineStatus::Halted);
+    assert_eq!(m.step_cycle(), MachineStatus::Halted);
+}
+
+#[test]
+fn machine_memory_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, 1000),
+        &[encode(LOAD, REG::A, REG::B)],
+        &[encode(INC, REG::A, REG::B)],
+        &[encode(STORE, REG::B, REG::A)],
+        &[encode(HLT, REG::A, REG::A)],
+    ]);
+    m.write_ram(1000, 41);
+    m.set_register(REG::E as usize, 9);
+    m.run();
+    assert_eq!(m.read_ram(1000), 42);
+    assert_eq!(m.register(REG::E as usize), 9);
+}
+
+#[test]
+fn alu_flags_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, SIGN_MASK - 1),               // 0
+        &ldcnst(REG::B, 1),                           // 2
+        &[encode(ADD, REG::A, REG::C)],               // 4
+        &ldcnst(REG::A, 0),                           // 5
+        &[encode(SUB, REG::A, REG::C)],               // 7
+        &ldcnst(REG::B, 0),                           // 8
+        &ldcnst(REG::E, 14),                          // 10
+        &[encode(SUB, REG::A, REG::C)],               // 12
+        &[encode(JZ, REG::A, REG::E)],                // 13
+        &[encode(HLT, REG::A, REG::A)],               // 14
+    ]);
+    let alu_flags = |m: &Machine| m.flags() >> CARRY_BIT_NUM & 0b1111;
+    for _ in 0..4 {
+        m.step_instruction();
+    }
+    // signed overflow into the sign bit
+    assert_eq!(alu_flags(&m), 0b1010);
+    m.step_instruction();
+    m.step_instruction();
+    // 0 - 1 borrows
+    assert_eq!(alu_flags(&m), 0b1001);
+    assert_eq!(m.register(REG::C as usize), crate::bits::WORD_MASK);
+    m.run();
+    assert_eq!(alu_flags(&m), 0b0100);
+    assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 15);
+    assert_eq!(m.flags() & (1 << EQUAL_BIT_NUM), 1 << EQUAL_BIT_NUM);
+}
+
+#[test]
+fn signed_arithmetic_test() {
+    let minus = |v: u32| v.wrapping_neg() & crate::bits::WORD_MASK;
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, minus(3)),                    // 0
+        &ldcnst(REG::B, 5),                           // 2
+        &[encode(IMUL, REG::A, REG::C)],              // 4
+        &[encode(MOV, REG::C, REG::A)],               // 5
+        &ldcnst(REG::B, 4),                           // 6
+        &[encode(IDIV, REG::A, REG::D)],              // 8
+        &ldcnst(REG::E, 13),                          // 9
+        &[encode(JLS, REG::A, REG::E)],               // 11
+        &[encode(HLT, REG::A, REG::A)],               // 12
+        &[encode(INC, REG::A, REG::E)],               // 13
+        &ldcnst(REG::C, 18),                          // 14
+        &[encode(JL, REG::A, REG::C)],                // 16
+        &[encode(INC, REG::A, REG::E)],               // 17
+        &[encode(HLT, REG::A, REG::A)],               // 18
+    ]);
+    m.run();
+    assert_eq!(m.register(REG::A as usize), minus(15));
+    assert_eq!(m.register(REG::D as usize), minus(3));
+    // jls was taken, jl wasn't
+    assert_eq!(m.register(REG::E as usize), 15);
+    assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 19);
+}
+
+#[test]
+fn bitwise_shift_test() {
+    let minus = |v: u32| v.wrapping_neg() & crate::bits::WORD_MASK;
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, 0b1100),                      // 0
+        &ldcnst(REG::B, 0b1010),                      // 2
+        &[encode(AND, REG::A, REG::C)],               // 4
+        &[encode(OR, REG::A, REG::D)],                // 5
+        &[encode(XOR, REG::A, REG::E)],               // 6
+        &[encode(PUSH, REG::E, REG::A)],              // 7
+        &ldcnst(REG::A, minus(8)),                    // 8
+        &ldcnst(REG::B, 2),                           // 10
+        &[encode(SAR, REG::A, REG::C)],               // 12
+        &[encode(SHR, REG::A, REG::D)],               // 13
+        &[encode(ROL, REG::A, REG::E)],               // 14
+        &[encode(PUSH, REG::C, REG::A)],              // 15
+        &ldcnst(REG::A, 1),                           // 16
+        &ldcnst(REG::B, BITNESS as u32),              // 18
+        &[encode(SHL, REG::A, REG::C)],               // 20
+        &[encode(ROR, REG::A, REG::D)],               // 21
+        &[encode(NOT, REG::A, REG::A)],               // 22
+        &[encode(HLT, REG::A, REG::A)],               // 23
+    ]);
+    m.run();
+    let mask = crate::bits::WORD_MASK;
+    assert_eq!(m.read_ram(RAM_SIZE as u32 - 1), 0b0110);
+    assert_eq!(m.read_ram(RAM_SIZE as u32 - 2), minus(2));
+    assert_eq!(m.register(REG::C as usize), 0);
+    assert_eq!(m.register(REG::D as usize), 1);
+    assert_eq!(m.register(REG::E as usize), minus(8) << 2 & mask | 0b11);
+    assert_eq!(m.register(REG::A as usize), mask - 1);
+    // bitwise operations clear carry and overflow
+    assert_eq!(m.flags() >> CARRY_BIT_NUM & 0b1111, 0b1000);
+}
+
+#[cfg(feature = "word32")]
+#[test]
+fn high_address_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::A, RAM_SIZE as u32 + 100),
+        &ldcnst(REG::B, 42),
+        &[encode(STORE, REG::B, REG::A)],
+        &[encode(LOAD, REG::A, REG::C)],
+        // the stack pointer wraps below 0 to the top of the word
+        &ldcnst(REG::SP, 0),
+        &[encode(PUSH, REG::B, REG::A)],
+        &[encode(PUSH, REG::C, REG::A)],
+        &[encode(POP, REG::A, REG::D)],
+        &[encode(HLT, REG::A, REG::A)],
+    ];
+    for engine in [Engine::Threaded, Engine::Sequential] {
+        let mut m = MachineBuilder::new().image(&program.concat()).engine(engine).build();
+        assert_eq!(m.run(), MachineStatus::Halted);
+        assert_eq!(m.read_ram(100), 42);
+        assert_eq!(m.register(REG::C as usize), 42);
+        assert_eq!(m.register(REG::D as usize), 42);
+        assert_eq!(m.read_ram(RAM_SIZE as u32 - 1), 42);
+    }
+    let mut interpreter = MachineBuilder::new().image(&program.concat()).build_interpreter();
+    assert_eq!(interpreter.run(), MachineStatus::Halted);
+    assert_eq!(interpreter.read_ram(100), 42);
+    assert_eq!(interpreter.register(REG::D as usize), 42);
+    assert_eq!(interpreter.read_ram(RAM_SIZE as u32 - 1), 42);
+}
+
+#[test]
+fn exception_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::A, 7),                           // 0
+        &ldcnst(REG::B, 0),                           // 2
+        &[encode(DIV, REG::A, REG::C)],               // 4
+        &[encode(INC, REG::A, REG::D)],               // 5
+        &[0b010001 << OPCODE_SHIFT],                  // 6
+        &[encode(HLT, REG::A, REG::A)],               // 7
+        &[encode(POP, REG::A, REG::E)],               // 8: divide error
+        &[encode(PUSH, REG::E, REG::A)],              // 9
+        &[encode(EOI, REG::A, REG::A)],               // 10
+    ];
+    for engine in [Engine::Threaded, Engine::Sequential] {
+        let mut m = MachineBuilder::new().image(&program.concat()).engine(engine).build();
+        install_vector(&m, Exception::DivideError.vector() as u32, 8);
+        assert_eq!(m.run(), MachineStatus::Faulted(Exception::InvalidOpcode));
+        // returned after the division, which wrote nothing
+        assert_eq!(m.register(REG::E as usize), 5);
+        assert_eq!(m.register(REG::D as usize), 1);
+        assert_eq!(m.register(REG::C as usize), 0);
+        assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 7);
+        assert_eq!(m.flags() & (1 << INTERRUPT_ENABLE_BIT_NUM), 1 << INTERRUPT_ENABLE_BIT_NUM);
+    }
+    let mut interpreter = MachineBuilder::new().image(&program.concat()).build_interpreter();
+    assert_eq!(interpreter.run(), MachineStatus::Faulted(Exception::DivideError));
+    assert_eq!(interpreter.register(PROGRAM_COUNTER_REG_NUM), 5);
+    assert_eq!(decode_instruction(0b010001 << OPCODE_SHIFT | 9 << DEST_SHIFT), "invalid(0b010001) a r9");
+}
+
+#[test]
+fn bus_test() {
+    let bus = Arc::new(Bus::new());
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let bus = bus.clone();
+            std::thread::spawn(move || {
+                let value = MValue::default();
+                bus.read_into(&value);
+                value.as_u32()
+            })
+        })
+        .collect();
+    bus.write_from(&MValue::from_u32(42));
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), 42);
+    }
+
+    let (alu_tx, alu_rx) = std::sync::mpsc::channel();
+    let alu_tx = Arc::new(parking_lot::Mutex::new(alu_tx));
+    let registers: Vec<Arc<dyn CpuComponent + Send + Sync>> = (0..3)
+        .map(|reg_num| {
+            Arc::new(RegisterComponent {
+                reg_num,
+                value: MValue::from_u32(reg_num as u32 + 7),
+                alu_tx: alu_tx.clone(),
+                sent_to_alu: Arc::new(AtomicUsize::new(0)),
+            }) as Arc<dyn CpuComponent + Send + Sync>
+        })
+        .collect();
+    let cables: ControlCables = array_init::array_init(|_| AtomicBool::new(false));
+    cables[reg_in(0)].store(true, SeqCst);
+    cables[reg_in(1)].store(true, SeqCst);
+    assert_eq!(bus_writer(&registers, &cables), Err(BusError::NoWriter));
+    cables[reg_out(2)].store(true, SeqCst);
+    assert_eq!(bus_writer(&registers, &cables), Ok(Some(2)));
+    // one cycle loads both readers
+    bus.start_cycle(None);
+    for r in registers.iter().rev() {
+        r.step(bus.clone(), &cables);
+    }
+    assert_eq!(alu_rx.try_iter().map(|(_, v)| v.as_u32()).collect::<Vec<_>>(), [9, 9]);
+    cables[reg_out(0)].store(true, SeqCst);
+    assert_eq!(bus_writer(&registers, &cables), Err(BusError::MultipleWriters(2)));
+}
+
+/// Blocks every write until the test releases it.
+struct SlowDevice {
+    release: parking_lot::Mutex<std::sync::mpsc::Receiver<()>>,
+}
+
+impl IoDevice for SlowDevice {
+    fn read(&self, _port: u32) -> u32 {
+        0
+    }
+
+    fn write(&self, _port: u32, _value: u32) {
+        let _ = self.release.lock().recv();
+    }
+}
+
+#[test]
+fn stuck_cycle_test() {
+    let bus = Bus::new();
+    bus.start_cycle(Some(Duration::from_millis(10)));
+    let value = MValue::from_u32(3);
+    bus.read_into(&value);
+    assert!(bus.timed_out());
+    assert_eq!(value.as_u32(), 3);
+
+    // the timeout is far above any cycle of a loaded machine, only the write
+    // to the device can reach it
+    let (release, rx) = std::sync::mpsc::channel();
+    let slow_device = SlowDevice {
+        release: parking_lot::Mutex::new(rx),
+    };
+    let mut m = MachineBuilder::new()
+        .image(&[&out(0x70, 5)[..], &[encode(HLT, REG::A, REG::A)]].concat())
+        .device(0x70..0x71, Arc::new(slow_device), None)
+        .cycle_timeout(Some(Duration::from_secs(1)))
+        .build();
+    assert_eq!(m.run(), MachineStatus::BusError(BusError::Timeout));
+    let report = m.report().unwrap();
+    assert!(report.starts_with("instruction: out"), "{}", report);
+    assert!(report.contains("RamIn MemoryIsIO"), "{}", report);
+    // the stuck component returns, so every thread is joined
+    release.send(()).unwrap();
+    drop(m);
+}
+
+#[test]
+fn exit_code_test() {
+    let program = [&out(EXIT_CODE_PORT, 3)[..], &[encode(HLT, REG::A, REG::A)]].concat();
+    for engine in [Engine::Threaded, Engine::Sequential] {
+        let mut m = MachineBuilder::new().image(&program).engine(engine).build();
+        assert_eq!(m.exit_code(), 0);
+        m.run();
+        assert_eq!(m.exit_code(), 3);
+        // joins the component threads
+        drop(m);
+    }
+    let mut interpreter = MachineBuilder::new().image(&program).build_interpreter();
+    interpreter.run();
+    assert_eq!(interpreter.exit_code(), 3);
+}
+
+#[test]
+fn debugger_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::C, 3),                           // 0
+        &ldcnst(REG::D, 7),                           // 2
+        &[encode(CALL, REG::A, REG::D)],              // 4
+        &[encode(INC, REG::A, REG::C)],               // 5
+        &[encode(HLT, REG::A, REG::A)],               // 6
+        &[encode(INC, REG::A, REG::C)],               // 7: twice
+        &[encode(INC, REG::A, REG::C)],               // 8
+        &[encode(POP, REG::A, REG::PC)],              // 9
+    ];
+    let m = MachineBuilder::new().image(&program.concat()).engine(Engine::Sequential).build();
+    let symbols = [("twice".to_string(), 7)].into_iter().collect();
+    let mut debugger = Debugger::new(m).with_symbols(symbols);
+    assert_eq!(debugger.stop_message(), "0: ldcnst a c");
+    assert_eq!(debugger.execute("break twice").unwrap(), "breakpoint at 7 <twice>: inc a c");
+    assert_eq!(debugger.execute("c").unwrap(), "breakpoint 7 <twice>: inc a c");
+    assert_eq!(debugger.execute("s 2").unwrap(), "9: pop a pc");
+    assert_eq!(debugger.execute("x 3 2").unwrap(), format!("3: 0x7 7\n4: {0:#x} {0}", encode(CALL, REG::A, REG::D)));
+    assert!(debugger.execute("regs").unwrap().starts_with("a     0\nb     0\nc     5\n"));
+    assert_eq!(debugger.execute("s").unwrap(), "5: inc a c");
+    // next runs the call at 4 again without stopping inside it
+    debugger.execute("delete twice").unwrap();
+    debugger.execute("set pc 4").unwrap();
+    assert_eq!(debugger.execute("next").unwrap(), "5: inc a c");
+    assert_eq!(debugger.machine().register(REG::C as usize), 7);
+    assert!(debugger.execute("disas").unwrap().contains("\n   3: constant 7\n   4: call a d\n=> 5: inc a c\n"));
+    // listings and dumps stop at the highest address
+    let listing = debugger.execute("disas 0xffffffff").unwrap();
+    assert!(listing.lines().last().unwrap().starts_with(&format!("   {}:", u32::MAX)), "{}", listing);
+    assert_eq!(debugger.execute("x 0xffffffff 2").unwrap().lines().count(), 1);
+    assert!(debugger.execute("x 0 100000").is_err());
+    let micro = debugger.execute("micro").unwrap();
+    assert!(micro.starts_with("step 0: c_inc"), "{}", micro);
+    debugger.execute("set 1000 0x2a").unwrap();
+    assert_eq!(debugger.machine().read_ram(1000), 42);
+    assert!(debugger.execute("bogus").is_err());
+    assert_eq!(debugger.execute("continue").unwrap(), "halted");
+}
+
+#[test]
+fn watchpoint_test() {
+    let m = build_machine(&[
+        &ldcnst(REG::D, 100),                         // 0
+        &ldcnst(REG::C, 3),                           // 2
+        &[encode(STORE, REG::C, REG::D)],             // 4
+        &[encode(LOAD, REG::D, REG::E)],              // 5
+        &[encode(INC, REG::A, REG::C)],               // 6
+        &[encode(INC, REG::A, REG::C)],               // 7
+        &[encode(HLT, REG::A, REG::A)],               // 8
+    ]);
+    let mut debugger = Debugger::new(m);
+    assert_eq!(debugger.execute("watch 100").unwrap(), "#1 watch 100 1");
+    assert_eq!(debugger.execute("rwatch 100").unwrap(), "#2 rwatch 100 1");
+    let stop = debugger.execute("continue").unwrap();
+    assert!(stop.starts_with("#1 watch 100 1: write of 3 at 100\nin store c d at microcode step"), "{}", stop);
+    let stop = debugger.execute("continue").unwrap();
+    assert!(stop.starts_with("#2 rwatch 100 1: read of 3 at 100\nin load d e"), "{}", stop);
+    assert_eq!(debugger.machine().register(REG::E as usize), 3);
+    debugger.execute("cbreak c_inc").unwrap();
+    debugger.execute("cond c > 4").unwrap();
+    assert!(debugger.execute("cbreak nothing").is_err());
+    assert!(debugger.execute("continue").unwrap().starts_with("#3 cbreak c_inc\nin inc a c"));
+    assert_eq!(debugger.machine().register(REG::C as usize), 4);
+    let stop = debugger.execute("continue").unwrap();
+    assert!(stop.starts_with("#3 cbreak c_inc\n#4 cond c > 4: c is 5\n"), "{}", stop);
+    assert_eq!(debugger.execute("break").unwrap(), "#1 watch 100 1\n#2 rwatch 100 1\n#3 cbreak c_inc\n#4 cond c > 4");
+    debugger.execute("delete #3").unwrap();
+    assert!(debugger.execute("delete #9").is_err());
+    assert_eq!(debugger.execute("continue").unwrap(), "halted");
+}
+
+#[test]
+fn snapshot_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, 17),
+        &ldcnst(REG::B, 5),
+        &[encode(DIVMOD, REG::B, REG::A)],
+        &[encode(PUSH, REG::A, REG::A)],
+        &[encode(HLT, REG::A, REG::A)],
+    ]);
+    m.step_instruction();
+    m.step_instruction();
+    m.step_instruction();
+    // stop between the quotient and the remainder of divmod
+    m.step_cycle();
+    let snapshot = m.snapshot();
+    assert_eq!(snapshot.registers[REG::A as usize], 3);
+    assert_eq!(snapshot.registers[REG::B as usize], 5);
+    assert_eq!(snapshot.alu_remainder, 2);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let loaded: Snapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded, snapshot);
+    let mut restored = MachineBuilder::new().engine(Engine::Sequential).build();
+    restored.restore(&loaded).unwrap();
+    assert!(m.snapshot().diff(&restored.snapshot()).is_empty());
+    assert_eq!(m.run(), MachineStatus::Halted);
+    assert_eq!(restored.run(), MachineStatus::Halted);
+    assert_eq!(restored.register(REG::A as usize), 3);
+    assert_eq!(restored.register(REG::B as usize), 2);
+    let end = restored.snapshot();
+    assert_eq!(m.snapshot(), end);
+    let top = RAM_SIZE as u32 - 1;
+    assert!(snapshot.diff(&end).contains(&format!("ram {}: 0 -> 3", top)));
+    restored.set_register(REG::C as usize, 9);
+    assert_eq!(end.diff(&restored.snapshot()), vec!["c: 0 -> 9"]);
+
+    // broken or foreign snapshots are refused before anything is restored
+    let broken = [
+        Snapshot { version: 0, ..snapshot.clone() },
+        Snapshot { registers: vec![0; REGISTERS_NUM - 1], ..snapshot.clone() },
+        Snapshot { microcode_counter: snapshot.microcodes.len() + 1, ..snapshot.clone() },
+        Snapshot { microcodes: vec![vec![CONTROL_CABLES_SIZE]], microcode_counter: 0, ..snapshot.clone() },
+    ];
+    for b in &broken {
+        assert!(restored.restore(b).is_err());
+    }
+    assert_eq!(restored.register(REG::C as usize), 9);
+    assert!(snapshot.diff(&broken[1]).contains(&format!("registers: {} -> {}", REGISTERS_NUM, REGISTERS_NUM - 1)));
+}
+
+/// Sends a gdb packet and returns the reply, checking the acks.
+fn gdb_request(stream: &mut TcpStream, data: &str) -> String {
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stream, "${}#{:02x}", data, sum).unwrap();
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        reply.push(byte[0]);
+        if reply.len() > 3 && reply[reply.len() - 3] == b'#' {
+            break;
+        }
+    }
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(reply.starts_with("+$"), "{}", reply);
+    reply[2..reply.len() - 3].to_string()
+}
+
+#[test]
+fn gdb_stub_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::C, 3),                           // 0
+        &ldcnst(REG::D, 7),                           // 2
+        &[encode(CALL, REG::A, REG::D)],              // 4
+        &[encode(INC, REG::A, REG::C)],               // 5
+        &[encode(HLT, REG::A, REG::A)],               // 6
+        &[encode(INC, REG::A, REG::C)],               // 7
+        &[encode(POP, REG::A, REG::PC)],              // 8
+    ];
+    let word = |value: u32| format!("{:0width$x}", value, width = WORD_BYTES * 2);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        assert!(gdb_request(&mut stream, "qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+        let xml = gdb_request(&mut stream, "qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"), "{}", xml);
+        assert!(xml.contains(&format!("<reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"5\"/>", BITNESS)));
+        assert!(xml.contains(&format!("<reg name=\"flags\" bitsize=\"{}\" type=\"int\" regnum=\"8\"/>", BITNESS)));
+        let first = gdb_request(&mut stream, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[1..17]));
+        assert_eq!(gdb_request(&mut stream, "?"), "S05");
+        let registers = gdb_request(&mut stream, "g");
+        assert_eq!(registers.len(), (REGISTERS_NUM + 1) * WORD_BYTES * 2);
+        assert_eq!(registers[5 * WORD_BYTES * 2..6 * WORD_BYTES * 2], word(0));
+        assert_eq!(gdb_request(&mut stream, "Z0,7,1"), "OK");
+        assert_eq!(gdb_request(&mut stream, "c"), "S05");
+        assert_eq!(gdb_request(&mut stream, "p5"), word(7));
+        assert_eq!(gdb_request(&mut stream, "P2=10"), "OK");
+        assert_eq!(gdb_request(&mut stream, "s"), "S05");
+        assert_eq!(gdb_request(&mut stream, "p2"), word(0x11));
+        assert_eq!(gdb_request(&mut stream, "m3,2"), word(7) + &word(encode(CALL, REG::A, REG::D)));
+        assert_eq!(gdb_request(&mut stream, &format!("M3e8,1:{}", word(42))), "OK");
+        // replies longer than a packet are refused
+        assert_eq!(gdb_request(&mut stream, "m0,ffffffff"), "E01");
+        assert_eq!(gdb_request(&mut stream, "z0,7,1"), "OK");
+        assert_eq!(gdb_request(&mut stream, "vMustReplyEmpty"), "");
+        assert_eq!(gdb_request(&mut stream, "c"), "W00");
+        assert_eq!(gdb_request(&mut stream, "D"), "OK");
+    });
+    let m = MachineBuilder::new().image(&program.concat()).engine(Engine::Sequential).build();
+    let mut stub = GdbStub::new(m);
+    stub.serve(listener.accept().unwrap().0).unwrap();
+    client.join().unwrap();
+    assert_eq!(stub.machine().read_ram(1000), 42);
+    assert_eq!(stub.machine().register(REG::C as usize), 0x12);
+}
+
+fn dap_send(stream: &mut TcpStream, seq: u64, command: &str, arguments: serde_json::Value) {
+    let body = serde_json::json!({
+        "seq": seq,
+        "type": "request",
+        "command": command,
+        "arguments": arguments,
+    })
+    .to_string();
+    write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+}
+
+fn dap_read(reader: &mut BufReader<TcpStream>) -> serde_json::Value {
+    let mut header = String::new();
+    reader.read_line(&mut header).unwrap();
+    let length = header.trim().strip_prefix("Content-Length: ").unwrap().parse().unwrap();
+    reader.read_line(&mut String::new()).unwrap();
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn dap_server_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::C, 3),                           // 0: line 1
+        &ldcnst(REG::D, 7),                           // 2: line 2
+        &[encode(CALL, REG::A, REG::D)],              // 4: line 3
+        &[encode(INC, REG::A, REG::C)],               // 5: line 4
+        &[encode(HLT, REG::A, REG::A)],               // 6: line 5, twice: on line 6
+        &[encode(INC, REG::A, REG::C)],               // 7: line 7
+        &[encode(INC, REG::A, REG::C)],               // 8: line 8
+        &[encode(POP, REG::A, REG::PC)],              // 9: line 9
+    ];
+    let symbols = Symbols {
+        source: Some("/tmp/twice.mmasm".into()),
+        labels: [("twice".to_string(), 7)].into_iter().collect(),
+        lines: [(0, 1), (2, 2), (4, 3), (5, 4), (6, 5), (7, 7), (8, 8), (9, 9)].into_iter().collect(),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read = || dap_read(&mut reader);
+        dap_send(&mut stream, 1, "initialize", serde_json::json!({"adapterID": "mmachine"}));
+        assert_eq!(read()["body"]["supportsReadMemoryRequest"], true);
+        assert_eq!(read()["event"], "initialized");
+        dap_send(&mut stream, 2, "launch", serde_json::json!({"stopOnEntry": true}));
+        assert_eq!(read()["success"], true);
+        let lines = serde_json::json!({"source": {"path": "/tmp/twice.mmasm"}, "breakpoints": [{"line": 6}, {"line": 20}]});
+        dap_send(&mut stream, 3, "setBreakpoints", lines);
+        let breakpoints = read()["body"]["breakpoints"].clone();
+        assert_eq!(breakpoints[0], serde_json::json!({"verified": true, "line": 7}));
+        assert_eq!(breakpoints[1]["verified"], false);
+        dap_send(&mut stream, 4, "configurationDone", serde_json::json!({}));
+        assert_eq!(read()["success"], true);
+        assert_eq!(read()["body"]["reason"], "entry");
+        dap_send(&mut stream, 5, "continue", serde_json::json!({"threadId": 1}));
+        assert_eq!(read()["success"], true);
+        assert_eq!(read()["body"]["reason"], "breakpoint");
+        dap_send(&mut stream, 6, "stackTrace", serde_json::json!({"threadId": 1}));
+        let frame = read()["body"]["stackFrames"][0].clone();
+        assert_eq!((frame["name"].as_str(), frame["line"].as_u64()), (Some("twice"), Some(7)));
+        assert_eq!(frame["source"]["name"], "twice.mmasm");
+        dap_send(&mut stream, 7, "variables", serde_json::json!({"variablesReference": 1}));
+        let variables = read()["body"]["variables"].clone();
+        assert_eq!(variables[2]["name"], "c");
+        assert_eq!(variables[2]["value"], "3 (0x3)");
+        assert_eq!(variables[REGISTERS_NUM]["name"], "flags");
+        dap_send(&mut stream, 8, "next", serde_json::json!({"threadId": 1}));
+        read();
+        assert_eq!(read()["body"]["reason"], "step");
+        dap_send(&mut stream, 9, "stepOut", serde_json::json!({"threadId": 1}));
+        read();
+        assert_eq!(read()["body"]["reason"], "step");
+        dap_send(&mut stream, 10, "stackTrace", serde_json::json!({"threadId": 1}));
+        assert_eq!(read()["body"]["stackFrames"][0]["line"], 4);
+        let word_bytes = WORD_BYTES as u64;
+        let memory = serde_json::json!({"memoryReference": "0x0", "offset": 3 * word_bytes, "count": word_bytes});
+        dap_send(&mut stream, 11, "readMemory", memory);
+        let data = read()["body"]["data"].as_str().unwrap().to_string();
+        assert_eq!(data, BASE64.encode(words_to_bytes(&[7])));
+        let last_word = (RAM_SIZE as u64 - 1) * word_bytes;
+        let memory = serde_json::json!({"memoryReference": format!("{:#x}", last_word), "count": 1u64 << 40});
+        dap_send(&mut stream, 11, "readMemory", memory);
+        let body = read()["body"].clone();
+        assert_eq!(body["data"].as_str().unwrap().len(), BASE64.encode(vec![0; WORD_BYTES]).len());
+        assert_eq!(body["unreadableBytes"], (1u64 << 40) - word_bytes);
+        let memory = serde_json::json!({
+            "memoryReference": format!("{:#x}", 1000 * word_bytes),
+            "data": BASE64.encode(words_to_bytes(&[42])),
+        });
+        dap_send(&mut stream, 12, "writeMemory", memory);
+        assert_eq!(read()["body"]["bytesWritten"], word_bytes);
+        dap_send(&mut stream, 13, "continue", serde_json::json!({"threadId": 1}));
+        read();
+        assert_eq!(read()["body"]["exitCode"], 0);
+        assert_eq!(read()["event"], "terminated");
+        dap_send(&mut stream, 14, "disconnect", serde_json::json!({}));
+        assert_eq!(read()["command"], "disconnect");
+    });
+    let m = MachineBuilder::new().image(&program.concat()).engine(Engine::Sequential).build();
+    let mut server = DapServer::new(m, symbols);
+    server.serve(listener.accept().unwrap().0).unwrap();
+    client.join().unwrap();
+    assert_eq!(server.machine().read_ram(1000), 42);
+    assert_eq!(server.machine().register(REG::C as usize), 6);
+}
+
+#[test]
+fn dap_pause_next_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::D, 4),                           // 0: line 1
+        &[encode(CALL, REG::A, REG::D)],              // 2: line 2
+        &[encode(HLT, REG::A, REG::A)],               // 3: line 3
+        &ldcnst(REG::PC, 4),                          // 4: line 4, never returns
+    ];
+    let symbols = Symbols {
+        source: Some("/tmp/forever.mmasm".into()),
+        lines: [(0, 1), (2, 2), (3, 3), (4, 4)].into_iter().collect(),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read = || dap_read(&mut reader);
+        dap_send(&mut stream, 1, "initialize", serde_json::json!({"adapterID": "mmachine"}));
+        read();
+        read();
+        dap_send(&mut stream, 2, "launch", serde_json::json!({"stopOnEntry": true}));
+        read();
+        dap_send(&mut stream, 3, "configurationDone", serde_json::json!({}));
+        read();
+        assert_eq!(read()["body"]["reason"], "entry");
+        dap_send(&mut stream, 4, "next", serde_json::json!({"threadId": 1}));
+        read();
+        assert_eq!(read()["body"]["reason"], "step");
+        // stepping over the call never ends without the pause
+        dap_send(&mut stream, 5, "next", serde_json::json!({"threadId": 1}));
+        read();
+        dap_send(&mut stream, 6, "pause", serde_json::json!({"threadId": 1}));
+        assert_eq!(read()["command"], "pause");
+        assert_eq!(read()["body"]["reason"], "pause");
+        dap_send(&mut stream, 7, "disconnect", serde_json::json!({}));
+        assert_eq!(read()["command"], "disconnect");
+    });
+    let m = MachineBuilder::new().image(&program.concat()).engine(Engine::Sequential).build();
+    let mut server = DapServer::new(m, symbols);
+    server.serve(listener.accept().unwrap().0).unwrap();
+    client.join().unwrap();
+    assert_eq!(server.machine().next_address(), 4);
+}
+
+fn install_vector(m: &Machine, vector: u32, handler: u32) {
+    m.write_ram(INTERRUPT_VECTOR_TABLE as u32 + vector, handler);
+}
+
+#[test]
+fn software_interrupt_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::C, 3),                        // 0
+        &[encode(INT, REG::C, REG::A)],            // 2
+        &[encode(HLT, REG::A, REG::A)],            // 3
+        &[encode(INC, REG::A, REG::D)],            // 4: handler for 3
+        &[encode(MOV, REG::SP, REG::E)],           // 5
+        &[encode(EOI, REG::A, REG::A)],            // 6
+    ]);
+    install_vector(&m, 3, 4);
+    m.run();
+    assert_eq!(m.register(REG::D as usize), 1);
+    assert_eq!(m.register(REG::E as usize), RAM_SIZE as u32 - 2);
+    assert_eq!(m.read_ram(RAM_SIZE as u32 - 1), 3);
+    assert_eq!(m.register(STACK_POINTER_REG_NUM), RAM_SIZE as u32 - 1);
+    assert_eq!(m.flags() & (1 << INTERRUPT_ENABLE_BIT_NUM), 1 << INTERRUPT_ENABLE_BIT_NUM);
+}
+
+#[test]
+fn masked_interrupt_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::C, 5),                        // 0
+        &[encode(CLI, REG::A, REG::A)],            // 2
+        &[encode(INT, REG::C, REG::A)],            // 3
+        &[encode(INC, REG::A, REG::E)],            // 4
+        &[encode(INT, REG::C, REG::A)],            // 5: handler for 5, masked
+        &[encode(HLT, REG::A, REG::A)],            // 6
+    ]);
+    install_vector(&m, 5, 4);
+    m.run();
+    assert_eq!(m.register(REG::E as usize), 1);
+    assert_eq!(m.register(STACK_POINTER_REG_NUM), RAM_SIZE as u32 - 1);
+    assert_eq!(m.flags() & (1 << INTERRUPT_ENABLE_BIT_NUM), 0);
+}
+
+#[test]
+fn nested_interrupt_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::C, 1),                        // 0
+        &ldcnst(REG::D, 2),                        // 2
+        &[encode(INT, REG::C, REG::A)],            // 4
+        &[encode(HLT, REG::A, REG::A)],            // 5
+        &[encode(INT, REG::D, REG::A)],            // 6: handler 1, masked
+        &[encode(STI, REG::A, REG::A)],            // 7
+        &[encode(INT, REG::D, REG::A)],            // 8
+        &[encode(INC, REG::A, REG::E)],            // 9
+        &[encode(EOI, REG::A, REG::A)],            // 10
+        &[encode(MOV, REG::SP, REG::B)],           // 11: handler 2
+        &[encode(INC, REG::A, REG::E)],            // 12
+        &[encode(EOI, REG::A, REG::A)],            // 13
+    ]);
+    install_vector(&m, 1, 6);
+    install_vector(&m, 2, 11);
+    m.run();
+    assert_eq!(m.register(REG::E as usize), 2);
+    assert_eq!(m.register(REG::B as usize), RAM_SIZE as u32 - 3);
+    assert_eq!(m.read_ram(RAM_SIZE as u32 - 1), 5);
+    assert_eq!(m.read_ram(RAM_SIZE as u32 - 2), 9);
+    assert_eq!(m.register(STACK_POINTER_REG_NUM), RAM_SIZE as u32 - 1);
+}
+
+#[test]
+fn irq_priority_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::D, 1000),                        // 0
+        &ldcnst(REG::A, PIC_PRIORITY_PORT_BASE + 2),  // 2
+        &ldcnst(REG::B, 0),                           // 4
+        &[encode(OUT, REG::A, REG::B)],               // 6
+        &ldcnst(REG::A, PIC_MASK_PORT),               // 7
+        &ldcnst(REG::B, 0b11111001),                  // 9
+        &[encode(OUT, REG::A, REG::B)],               // 11
+        &ldcnst(REG::A, PIC_PENDING_PORT),            // 12
+        &[encode(IN, REG::A, REG::E)],                // 14
+        &[encode(HLT, REG::A, REG::A)],               // 15
+        &ldcnst(REG::C, 1),                           // 16: line 1
+        &[encode(STORE, REG::C, REG::D)],             // 18
+        &[encode(INC, REG::A, REG::D)],               // 19
+        &[encode(EOI, REG::A, REG::A)],               // 20
+        &ldcnst(REG::C, 2),                           // 21: line 2
+        &[encode(STORE, REG::C, REG::D)],             // 23
+        &[encode(INC, REG::A, REG::D)],               // 24
+        &[encode(EOI, REG::A, REG::A)],               // 25
+    ]);
+    install_vector(&m, IRQ_VECTOR_BASE as u32 + 1, 16);
+    install_vector(&m, IRQ_VECTOR_BASE as u32 + 2, 21);
+    m.interrupt_controller().raise(1);
+    m.interrupt_controller().raise(2);
+    m.interrupt_controller().raise(4);
+    m.run();
+    assert_eq!(m.read_ram(1000), 2);
+    assert_eq!(m.read_ram(1001), 1);
+    assert_eq!(m.read_ram(1002), 0);
+    assert_eq!(m.register(REG::E as usize), 1 << 4);
+    assert_eq!(m.register(STACK_POINTER_REG_NUM), RAM_SIZE as u32 - 1);
+}
+
+#[test]
+fn irq_disabled_test() {
+    let mut m = build_machine(&[
+        &[encode(CLI, REG::A, REG::A)],               // 0
+        &ldcnst(REG::A, PIC_MASK_PORT),               // 1
+        &ldcnst(REG::B, 0),                           // 3
+        &[encode(OUT, REG::A, REG::B)],               // 5
+        &[encode(HLT, REG::A, REG::A)],               // 6
+        &[encode(INC, REG::A, REG::E)],               // 7: line 0
+        &[encode(EOI, REG::A, REG::A)],               // 8
+    ]);
+    install_vector(&m, IRQ_VECTOR_BASE as u32, 7);
+    m.interrupt_controller().raise(0);
+    m.run();
+    assert_eq!(m.register(REG::E as usize), 0);
+    assert_eq!(m.interrupt_controller().pending(), 1);
+    assert_eq!(m.interrupt_controller().mask(), 0);
+}
+
+#[test]
+fn periodic_timer_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, TIMER_RELOAD_PORT),           // 0
+        &ldcnst(REG::B, 40),                          // 2
+        &[encode(OUT, REG::A, REG::B)],               // 4
+        &ldcnst(REG::A, TIMER_CONTROL_PORT),          // 5
+        &ldcnst(REG::B, TIMER_ENABLE | TIMER_PERIODIC), // 7
+        &[encode(OUT, REG::A, REG::B)],               // 9
+        &ldcnst(REG::A, PIC_MASK_PORT),               // 10
+        &ldcnst(REG::B, 0b11111110),                  // 12
+        &[encode(OUT, REG::A, REG::B)],               // 14
+        &ldcnst(REG::B, 3),                           // 15
+        &ldcnst(REG::C, 19),                          // 17
+        &[encode(MOV, REG::E, REG::A)],               // 19: loop
+        &[encode(JL, REG::A, REG::C)],                // 20
+        &[encode(HLT, REG::A, REG::A)],               // 21
+        &[encode(INC, REG::A, REG::E)],               // 22: line 0
+        &[encode(EOI, REG::A, REG::A)],               // 23
+    ]);
+    install_vector(&m, (IRQ_VECTOR_BASE + TIMER_IRQ_LINE) as u32, 22);
+    m.run();
+    assert_eq!(m.register(REG::A as usize), 3);
+    assert_eq!(m.register(REG::E as usize), 3);
+    assert_eq!(m.timer().control(), TIMER_ENABLE | TIMER_PERIODIC);
+    assert_eq!(m.timer().status(), TIMER_EXPIRED);
+}
+
+#[test]
+fn one_shot_timer_test() {
+    let mut m = build_machine(&[
+        &ldcnst(REG::A, TIMER_RELOAD_PORT),           // 0
+        &ldcnst(REG::B, 5),                           // 2
+        &[encode(OUT, REG::A, REG::B)],               // 4
+        &ldcnst(REG::A, TIMER_CONTROL_PORT),          // 5
+        &ldcnst(REG::B, TIMER_ENABLE),                // 7
+        &[encode(OUT, REG::A, REG::B)],               // 9
+        &ldcnst(REG::D, TIMER_STATUS_PORT),           // 10
+        &ldcnst(REG::B, TIMER_EXPIRED),               // 12
+        &ldcnst(REG::C, 16),                          // 14
+        &[encode(IN, REG::D, REG::A)],                // 16: loop
+        &[encode(JNE, REG::A, REG::C)],               // 17
+        &[encode(OUT, REG::D, REG::B)],               // 18
+        &[encode(HLT, REG::A, REG::A)],               // 19
+    ]);
+    m.run();
+    assert_eq!(m.timer().control(), 0);
+    assert_eq!(m.timer().status(), 0);
+    // the line stays pending while it is masked
+    assert_eq!(m.interrupt_controller().pending(), 1 << TIMER_IRQ_LINE);
+}
+
+#[derive(Default)]
+struct EchoDevice {
+    last: AtomicU32,
+    ticks: AtomicU32,
+}
+
+impl IoDevice for EchoDevice {
+    fn read(&self, port: u32) -> u32 {
+        self.last.load(SeqCst) + port
+    }
+
+    fn write(&self, _port: u32, value: u32) {
+        self.last.store(value, SeqCst);
+    }
+
+    fn tick(&self) {
+        self.ticks.fetch_add(1, SeqCst);
+    }
+
+    fn irq(&self) -> bool {
+        self.ticks.load(SeqCst) == 30
+    }
+}
+
+#[test]
+fn io_device_test() {
+    let recorder = Arc::new(OutputRecorder::new());
+    let echo = Arc::new(EchoDevice::default());
+    let mut m = MachineBuilder::new()
+        .image(
+            &[
+                &ldcnst(REG::A, 0x70)[..],                // 0
+                &ldcnst(REG::B, 7),                       // 2
+                &[encode(OUT, REG::A, REG::B)],           // 4
+                &[encode(IN, REG::A, REG::C)],            // 5
+                &ldcnst(REG::D, 1),                       // 6
+                &ldcnst(REG::E, 104),                     // 8
+                &[encode(OUT, REG::D, REG::E)],           // 10
+                &ldcnst(REG::E, 105),                     // 11
+                &[encode(OUT, REG::D, REG::E)],           // 13
+                &ldcnst(REG::A, 0x99),                    // 14
+                &[encode(IN, REG::A, REG::B)],            // 16
+                &[encode(HLT, REG::A, REG::A)],           // 17
+            ]
+            .concat(),
+        )
+        .device(CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1, recorder.clone(), None)
+        .device(0x70..0x71, echo.clone(), Some(5))
+        .build();
+    m.run();
+    assert_eq!(m.register(REG::C as usize), 7 + 0x70);
+    // unmapped ports read as 0
+    assert_eq!(m.register(REG::B as usize), 0);
+    assert_eq!(recorder.text(), "hi");
+    assert_eq!(recorder.writes(), vec![(1, 104), (1, 105)]);
+    assert!(echo.ticks.load(SeqCst) > 30);
+    assert_eq!(m.interrupt_controller().pending(), 1 << 5);
+}
+
+#[test]
+fn console_input_test() {
+    let recorder = Arc::new(OutputRecorder::new());
+    let input = ConsoleInput::spawn(std::io::Cursor::new(b"abc".to_vec()));
+    let mut m = MachineBuilder::new()
+        .image(
+            &[
+                &ldcnst(REG::D, CONSOLE_INPUT_PORT)[..],  // 0
+                &ldcnst(REG::E, CONSOLE_OUTPUT_PORT),     // 2
+                &ldcnst(REG::B, CONSOLE_EOF_VALUE),       // 4
+                &ldcnst(REG::C, 13),                      // 6
+                &[encode(IN, REG::D, REG::A)],            // 8: loop
+                &[encode(JE, REG::A, REG::C)],            // 9
+                &[encode(OUT, REG::E, REG::A)],           // 10
+                &ldcnst(REG::PC, 8),                      // 11
+                &ldcnst(REG::D, CONSOLE_STATUS_PORT),     // 13
+                &[encode(IN, REG::D, REG::C)],            // 15
+                &[encode(HLT, REG::A, REG::A)],           // 16
+            ]
+            .concat(),
+        )
+        .device(CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1, recorder.clone(), None)
+        .device(CONSOLE_INPUT_PORTS, input, Some(CONSOLE_IRQ_LINE))
+        .build();
+    m.run();
+    assert_eq!(recorder.text(), "abc");
+    assert_eq!(m.register(REG::C as usize), CONSOLE_EOF);
+    assert_eq!(m.interrupt_controller().pending(), 1 << CONSOLE_IRQ_LINE);
+}
+
+#[test]
+fn console_status_test() {
+    let input = ConsoleInput::from_bytes(b"x");
+    assert_eq!(input.read(CONSOLE_STATUS_PORT), CONSOLE_BYTE_AVAILABLE);
+    assert!(input.irq());
+    assert!(!input.irq());
+    assert_eq!(input.read(CONSOLE_INPUT_PORT), 'x' as u32);
+    assert_eq!(input.read(CONSOLE_STATUS_PORT), CONSOLE_EOF);
+    assert_eq!(input.read(CONSOLE_INPUT_PORT), CONSOLE_EOF_VALUE);
+}
+
+/// Writes a constant to a port, clobbers a and b.
+fn out(port: u32, value: u32) -> Vec<u32> {
+    [&ldcnst(REG::A, port)[..], &ldcnst(REG::B, value), &[encode(OUT, REG::A, REG::B)]].concat()
+}
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("mmachine-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn block_device_test() {
+    let path = temp_path("disk.img");
+    let mut image = vec![0; SECTOR_WORDS * 3];
+    image[SECTOR_WORDS..SECTOR_WORDS + 2].copy_from_slice(&[0x1234, 0x0101]);
+    std::fs::write(&path, words_to_bytes(&image)).unwrap();
+    let disk = BlockDevice::open(&path).unwrap();
+    let mut m = MachineBuilder::new()
+        .image(
+            &[
+                out(DISK_SECTOR_PORT, 1),
+                out(DISK_COMMAND_PORT, DISK_READ),
+                ldcnst(REG::A, DISK_DATA_PORT).to_vec(),
+                vec![encode(IN, REG::A, REG::C), encode(IN, REG::A, REG::D)],
+                vec![encode(MOV, REG::C, REG::A), encode(MOV, REG::D, REG::B)],
+                vec![encode(ADD, REG::A, REG::E)],
+                out(DISK_INDEX_PORT, 0),
+                ldcnst(REG::A, DISK_DATA_PORT).to_vec(),
+                vec![encode(OUT, REG::A, REG::E)],
+                out(DISK_SECTOR_PORT, 2),
+                out(DISK_COMMAND_PORT, DISK_WRITE),
+                out(DISK_SECTOR_PORT, 9),
+                out(DISK_COMMAND_PORT, DISK_READ),
+                ldcnst(REG::A, DISK_STATUS_PORT).to_vec(),
+                vec![encode(IN, REG::A, REG::B)],
+                ldcnst(REG::A, DISK_SIZE_PORT).to_vec(),
+                vec![encode(IN, REG::A, REG::A)],
+                vec![encode(HLT, REG::A, REG::A)],
+            ]
+            .concat(),
+        )
+        .device(DISK_PORTS, disk, Some(DISK_IRQ_LINE))
+        .build();
+    m.run();
+    let written = words_from_bytes(&std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(m.register(REG::C as usize), 0x1234);
+    assert_eq!(m.register(REG::D as usize), 0x0101);
+    assert_eq!(m.register(REG::B as usize), DISK_ERROR);
+    assert_eq!(m.register(REG::A as usize), 3);
+    assert_eq!(&written[SECTOR_WORDS * 2..SECTOR_WORDS * 2 + 3], &[0x1335, 0x0101, 0]);
+    assert_eq!(written.len(), image.len());
+    assert_eq!(m.interrupt_controller().pending(), 1 << DISK_IRQ_LINE);
+}
+
+#[test]
+fn text_display_test() {
+    let display = Arc::new(TextDisplay::new(8, 3));
+    let mut m = MachineBuilder::new()
+        .image(
+            &[
+                out(DISPLAY_CONTROL_PORT, DISPLAY_CLEAR),
+                out(DISPLAY_CURSOR_PORT, 8 + 2),
+                out(DISPLAY_DATA_PORT, 'h' as u32),
+                out(DISPLAY_DATA_PORT, 'i' as u32),
+                out(DISPLAY_DATA_PORT, '\n' as u32),
+                out(DISPLAY_DATA_PORT, '!' as u32),
+                ldcnst(REG::A, DISPLAY_WIDTH_PORT).to_vec(),
+                vec![encode(IN, REG::A, REG::C)],
+                ldcnst(REG::A, DISPLAY_CURSOR_PORT).to_vec(),
+                vec![encode(IN, REG::A, REG::D)],
+                vec![encode(HLT, REG::A, REG::A)],
+            ]
+            .concat(),
+        )
+        .device(DISPLAY_PORTS, display.clone(), None)
+        .build();
+    m.run();
+    assert_eq!(display.snapshot(), "\n  hi\n!\n");
+    assert_eq!(m.register(REG::C as usize), 8);
+    assert_eq!(m.register(REG::D as usize), 17);
+
+    // writing past the last cell scrolls the screen
+    for c in "abcdefgh".chars() {
+        display.write(DISPLAY_DATA_PORT, c as u32);
+    }
+    assert_eq!(display.snapshot(), "  hi\n!abcdefg\nh\n");
+    assert_eq!(display.cursor(), 17);
+    let mut rendered = Vec::new();
+    display.render(&mut rendered).unwrap();
+    assert!(String::from_utf8(rendered).unwrap().ends_with("\x1b[3;2H"));
+}
+
+#[test]
+#[should_panic(expected = "the display can't be empty")]
+fn empty_text_display_test() {
+    TextDisplay::new(0, 3);
+}
+
+/// Registers and flags after every cycle, then the whole RAM and the output.
+type Trace = (Vec<Vec<u32>>, Vec<u32>, Vec<(u32, u32)>);
+
+fn trace(engine: Engine, program: &[&[u32]], vectors: &[(u32, u32)]) -> Trace {
+    let output = Arc::new(OutputRecorder::new());
+    let mut m = MachineBuilder::new()
+        .image(&program.concat())
+        .engine(engine)
+        .device(CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1, output.clone(), None)
+        .build();
+    for (vector, handler) in vectors {
+        install_vector(&m, *vector, *handler);
+    }
+    let mut cycles = Vec::new();
+    while m.step_cycle() == MachineStatus::Running {
+        let mut state: Vec<u32> = (0..REGISTERS_NUM).map(|r| m.register(r)).collect();
+        state.push(m.flags());
+        cycles.push(state);
+    }
+    let ram = (0..RAM_SIZE as u32).map(|a| m.read_ram(a)).collect();
+    (cycles, ram, output.writes())
+}
+
+#[test]
+fn sequential_engine_test() {
+    let program: &[&[u32]] = &[
+        &out(TIMER_RELOAD_PORT, 25),                  // 0
+        &out(TIMER_CONTROL_PORT, TIMER_ENABLE | TIMER_PERIODIC), // 5
+        &out(PIC_MASK_PORT, 0b11111110),              // 10
+        &ldcnst(REG::C, 9),                           // 15
+        &ldcnst(REG::D, 33),                          // 17: loop
+        &[encode(CALL, REG::A, REG::D)],              // 19
+        &[encode(DEC, REG::A, REG::C)],               // 20
+        &[encode(MOV, REG::C, REG::A)],               // 21
+        &ldcnst(REG::B, 0),                           // 22
+        &ldcnst(REG::D, 17),                          // 24
+        &[encode(JNE, REG::A, REG::D)],               // 26
+        &ldcnst(REG::A, 1000),                        // 27
+        &[encode(STORE, REG::E, REG::A)],             // 29
+        &[encode(HLT, REG::A, REG::A)],               // 30
+        &[encode(INC, REG::A, REG::E)],               // 31: line 0
+        &[encode(EOI, REG::A, REG::A)],               // 32
+        &[encode(MOV, REG::C, REG::A)],               // 33: prints c
+        &ldcnst(REG::B, '0' as u32),                  // 34
+        &[encode(ADD, REG::A, REG::D)],               // 36
+        &ldcnst(REG::A, CONSOLE_OUTPUT_PORT),         // 37
+        &[encode(OUT, REG::A, REG::D)],               // 39
+        &[encode(PUSH, REG::D, REG::A)],              // 40
+        &[encode(POP, REG::A, REG::B)],               // 41
+        &[encode(POP, REG::A, REG::PC)],              // 42
+    ];
+    let vectors = [(IRQ_VECTOR_BASE as u32, 31)];
+    let threaded = trace(Engine::Threaded, program, &vectors);
+    let sequential = trace(Engine::Sequential, program, &vectors);
+    let text: String = sequential.2.iter().map(|(_, v)| (*v as u8) as char).collect();
+    assert_eq!(text, "987654321");
+    assert!(sequential.1[1000] > 0);
+    assert_eq!(threaded.0.len(), sequential.0.len());
+    assert!(threaded.0 == sequential.0);
+    assert!(threaded.1 == sequential.1);
+    assert_eq!(threaded.2, sequential.2);
+}
+
+/// Runs the program on the microcoded cpu and the interpreter, comparing them
+/// after every instruction.
+fn differential(
+    program: &[u32],
+    vectors: &[(u32, u32)],
+    irq_lines: &[usize],
+) -> Vec<(u32, u32)> {
+    let machine_output = Arc::new(OutputRecorder::new());
+    let interpreter_output = Arc::new(OutputRecorder::new());
+    let port = CONSOLE_OUTPUT_PORT..CONSOLE_OUTPUT_PORT + 1;
+    let mut m = MachineBuilder::new()
+        .image(program)
+        .engine(Engine::Sequential)
+        .device(port.clone(), machine_output.clone(), None)
+        .build();
+    let mut interpreter = MachineBuilder::new()
+        .image(program)
+        .device(port, interpreter_output.clone(), None)
+        .build_interpreter();
+    for (vector, handler) in vectors {
+        install_vector(&m, *vector, *handler);
+        interpreter.write_ram(INTERRUPT_VECTOR_TABLE as u32 + vector, *handler);
+    }
+    for line in irq_lines {
+        m.interrupt_controller().raise(*line);
+        interpreter.interrupt_controller().raise(*line);
+    }
+    // the cpu fetches ahead, its pc is one past the interpreter's
+    m.step_instruction();
+    while interpreter.status() == MachineStatus::Running {
+        assert!(interpreter.instructions() < 100000);
+        interpreter.step();
+        m.step_instruction();
+        assert_eq!(m.status(), interpreter.status());
+        for r in 0..PROGRAM_COUNTER_REG_NUM {
+            assert_eq!(m.register(r), interpreter.register(r), "register {}", r);
+        }
+        let fetched = if m.status() == MachineStatus::Running { 1 } else { 0 };
+        assert_eq!(
+            m.register(PROGRAM_COUNTER_REG_NUM),
+            interpreter.register(PROGRAM_COUNTER_REG_NUM) + fetched
+        );
+        assert_eq!(
+            m.register(STACK_POINTER_REG_NUM),
+            interpreter.register(STACK_POINTER_REG_NUM)
+        );
+        assert_eq!(m.flags(), interpreter.flags());
+    }
+    assert!((0..RAM_SIZE as u32).all(|a| m.read_ram(a) == interpreter.read_ram(a)));
+    assert_eq!(machine_output.writes(), interpreter_output.writes());
+    interpreter_output.writes()
+}
+
+/// A random straight line program with forward conditional jumps, it only
+/// touches addresses 1000..1100 and keeps the stack balanced. Divide errors
+/// return to the next instruction through the EOI after the final HLT.
+fn random_program(seed: u64) -> Vec<u32> {
+    let mut state = seed;
+    let mut next = |n: u32| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((state >> 33) % n as u64) as u32
+    };
+    let regs = [REG::A, REG::B, REG::C, REG::D];
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    for _ in 0..200 {
+        let src = regs[next(4) as usize];
+        let dst = regs[next(4) as usize];
+        match next(11) {
+            0 => ret.push(encode(MOV, src, dst)),
+            1 => {
+                let op = [
+                    ADD, SUB, MUL, DIV, IMUL, IDIV, MOD, DIVMOD, AND, OR, XOR, NOT, SHL, SHR, SAR,
+                    ROL, ROR,
+                ];
+                ret.push(encode(op[next(17) as usize], src, dst));
+            }
+            2 => ret.push(encode([INC, DEC][next(2) as usize], src, dst)),
+            3 => ret.extend(ldcnst(dst, next(crate::bits::WORD_MASK))),
+            4 if depth < 20 => {
+                depth += 1;
+                ret.push(encode(PUSH, src, dst));
+            }
+            5 if depth > 0 => {
+                depth -= 1;
+                ret.push(encode(POP, src, dst));
+            }
+            6 => {
+                ret.extend(ldcnst(REG::E, 1000 + next(100)));
+                ret.push(encode(STORE, src, REG::E));
+            }
+            7 => {
+                ret.extend(ldcnst(REG::E, 1000 + next(100)));
+                ret.push(encode(LOAD, REG::E, dst));
+            }
+            8 => {
+                ret.extend(ldcnst(REG::E, CONSOLE_OUTPUT_PORT));
+                ret.push(encode(OUT, REG::E, src));
+            }
+            9 => {
+                // skips the next instruction when taken
+                let target = ret.len() as u32 + 4;
+                ret.extend(ldcnst(REG::E, target));
+                let jump = [JE, JNE, JG, JGE, JL, JLE, JGS, JGES, JLS, JLES, JC, JO, JZ, JS]
+                    [next(14) as usize];
+                ret.push(encode(jump, src, REG::E));
+                ret.push(encode(INC, src, dst));
+            }
+            _ => ret.extend(ldcnst(dst, next(10))),
+        }
+    }
+    ret.push(encode(HLT, REG::A, REG::A));
+    ret.push(encode(EOI, REG::A, REG::A));
+    ret
+}
+
+#[test]
+fn interpreter_random_test() {
+    for seed in 0..20 {
+        let program = random_program(seed);
+        let divide_error = (Exception::DivideError.vector() as u32, program.len() as u32 - 1);
+        differential(&program, &[divide_error], &[]);
+    }
+}
+
+#[test]
+fn engines_random_test() {
+    for seed in 0..20 {
+        let program = random_program(seed);
+        let divide_error = (Exception::DivideError.vector() as u32, program.len() as u32 - 1);
+        let threaded = trace(Engine::Threaded, &[&program], &[divide_error]);
+        let sequential = trace(Engine::Sequential, &[&program], &[divide_error]);
+        assert!(threaded == sequential, "seed {}", seed);
+    }
+}
+
+#[test]
+fn divmod_test() {
+    let program: &[&[u32]] = &[
+        &ldcnst(REG::A, 4096),                        // 0
+        &ldcnst(REG::B, 10),                          // 2
+        &ldcnst(REG::C, 0),                           // 4
+        &[encode(DIVMOD, REG::D, REG::A)],            // 6: digits
+        &[encode(PUSH, REG::D, REG::A)],              // 7
+        &[encode(INC, REG::A, REG::C)],               // 8
+        &ldcnst(REG::B, 0),                           // 9
+        &ldcnst(REG::E, 18),                          // 11
+        &[encode(JE, REG::A, REG::E)],                // 13
+        &ldcnst(REG::B, 10),                          // 14
+        &ldcnst(REG::PC, 6),                          // 16
+        &[encode(POP, REG::A, REG::A)],               // 18: print
+        &ldcnst(REG::B, 48),                          // 19
+        &[encode(ADD, REG::A, REG::D)],               // 21
+        &ldcnst(REG::E, CONSOLE_OUTPUT_PORT),         // 22
+        &[encode(OUT, REG::E, REG::D)],               // 24
+        &[encode(DEC, REG::A, REG::C)],               // 25
+        &[encode(MOV, REG::C, REG::A)],               // 26
+        &ldcnst(REG::B, 0),                           // 27
+        &ldcnst(REG::E, 18),                          // 29
+        &[encode(JNE, REG::A, REG::E)],               // 31
+        &ldcnst(REG::A, 47),                          // 32
+        &ldcnst(REG::B, 5),                           // 34
+        &[encode(MOD, REG::A, REG::C)],               // 36
+        &[encode(HLT, REG::A, REG::A)],               // 37
+    ];
+    let output = differential(&program.concat(), &[], &[]);
+    let text: String = output.iter().map(|(_, v)| (*v as u8) as char).collect();
+    assert_eq!(text, "4096");
+    let mut m = build_machine(program);
+    m.run();
+    assert_eq!(m.register(REG::C as usize), 2);
+}
+
+#[test]
+fn interpreter_interrupt_test() {
+    let program: &[&[u32]] = &[
+        &out(PIC_MASK_PORT, 0b11111101),              // 0: line 1 is pending
+        &ldcnst(REG::C, 5),                           // 5
+        &ldcnst(REG::D, 26),                          // 7: loop
+        &[encode(CALL, REG::A, REG::D)],              // 9
+        &[encode(DEC, REG::A, REG::C)],               // 10
+        &[encode(MOV, REG::C, REG::A)],               // 11
+        &ldcnst(REG::B, 0),                           // 12
+        &ldcnst(REG::D, 7),                           // 14
+        &[encode(JNE, REG::A, REG::D)],               // 16
+        &ldcnst(REG::D, 3),                           // 17
+        &[encode(INT, REG::D, REG::A)],               // 19
+        &[encode(HLT, REG::A, REG::A)],               // 20
+        &[encode(INC, REG::A, REG::E)],               // 21: line 1 and vector 3
+        &[encode(PUSH, REG::E, REG::A)],              // 22
+        &[encode(POP, REG::A, REG::B)],               // 23
+        &[encode(MUL, REG::A, REG::E)],               // 24
+        &[encode(EOI, REG::A, REG::A)],               // 25
+        &[encode(MOV, REG::C, REG::A)],               // 26: prints c
+        &ldcnst(REG::B, '0' as u32),                  // 27
+        &[encode(ADD, REG::A, REG::D)],               // 29
+        &ldcnst(REG::A, CONSOLE_OUTPUT_PORT),         // 30
+        &[encode(OUT, REG::A, REG::D)],               // 32
+        &[encode(POP, REG::A, REG::PC)],              // 33
+    ];
+    let vectors = [((IRQ_VECTOR_BASE + 1) as u32, 21), (3, 21)];
+    let writes = differential(&program.concat(), &vectors, &[1]);
+    let text: String = writes.iter().map(|(_, v)| (*v as u8) as char).collect();
+    assert_eq!(text, "54321");
+}
+
+#[test]
+fn interpreter_image_test() {
+    let mut image = vec![0; RAM_SIZE + 2];
+    image[RAM_SIZE] = 5;
+    image[RAM_SIZE + 1] = u32::MAX;
+    let m = MachineBuilder::new().image(&image).build();
+    let interpreter = MachineBuilder::new().image(&image).build_interpreter();
+    // both wrap the image around the ram and mask it to words
+    for address in 0..2 {
+        assert_eq!(interpreter.read_ram(address), m.read_ram(address));
+    }
+    assert_eq!(interpreter.read_ram(0), 5);
+    assert_eq!(interpreter.read_ram(1), crate::bits::WORD_MASK);
+}
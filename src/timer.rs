@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::atomic::Ordering::SeqCst;
+
+use crate::io::IoDevice;
+
+pub const TIMER_IRQ_LINE: usize = 0;
+
+pub const TIMER_PORTS: std::ops::Range<u32> = 0x40..0x44;
+
+/// read/write: the number of clock cycles between expirations
+pub const TIMER_RELOAD_PORT: u32 = 0x40;
+/// read/write: TIMER_ENABLE | TIMER_PERIODIC, enabling reloads the counter
+pub const TIMER_CONTROL_PORT: u32 = 0x41;
+/// read: TIMER_EXPIRED, write: clears the given status bits
+pub const TIMER_STATUS_PORT: u32 = 0x42;
+/// read: the current counter
+pub const TIMER_COUNTER_PORT: u32 = 0x43;
+
+pub const TIMER_ENABLE: u32 = 1;
+/// restart from the reload value after expiring instead of disabling the timer
+pub const TIMER_PERIODIC: u32 = 2;
+
+pub const TIMER_EXPIRED: u32 = 1;
+
+/// Counts control clock cycles and raises TIMER_IRQ_LINE when the counter
+/// reaches zero.
+#[derive(Default)]
+pub struct Timer {
+    reload: AtomicU32,
+    counter: AtomicU32,
+    control: AtomicU32,
+    status: AtomicU32,
+    irq_request: AtomicBool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_reload(&self, reload: u32) {
+        self.reload.store(reload, SeqCst);
+    }
+
+    pub fn set_control(&self, control: u32) {
+        if control & TIMER_ENABLE != 0 {
+            self.counter.store(self.reload.load(SeqCst), SeqCst);
+        }
+        self.control.store(control & (TIMER_ENABLE | TIMER_PERIODIC), SeqCst);
+    }
+
+    pub fn control(&self) -> u32 {
+        self.control.load(SeqCst)
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status.load(SeqCst)
+    }
+
+    pub fn counter(&self) -> u32 {
+        self.counter.load(SeqCst)
+    }
+}
+
+impl IoDevice for Timer {
+    fn read(&self, port: u32) -> u32 {
+        match port {
+            TIMER_RELOAD_PORT => self.reload.load(SeqCst),
+            TIMER_CONTROL_PORT => self.control(),
+            TIMER_STATUS_PORT => self.status(),
+            TIMER_COUNTER_PORT => self.counter(),
+            _ => 0,
+        }
+    }
+
+    fn write(&self, port: u32, value: u32) {
+        match port {
+            TIMER_RELOAD_PORT => self.set_reload(value),
+            TIMER_CONTROL_PORT => self.set_control(value),
+            TIMER_STATUS_PORT => {
+                self.status.fetch_and(!value, SeqCst);
+            }
+            _ => {}
+        }
+    }
+
+    /// Counts one clock cycle.
+    fn tick(&self) {
+        let control = self.control.load(SeqCst);
+        if control & TIMER_ENABLE == 0 || self.reload.load(SeqCst) == 0 {
+            return;
+        }
+        if self.counter.fetch_sub(1, SeqCst) > 1 {
+            return;
+        }
+        self.status.fetch_or(TIMER_EXPIRED, SeqCst);
+        self.irq_request.store(true, SeqCst);
+        if control & TIMER_PERIODIC != 0 {
+            self.counter.store(self.reload.load(SeqCst), SeqCst);
+        } else {
+            self.control.store(control & !TIMER_ENABLE, SeqCst);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_request.swap(false, SeqCst)
+    }
+}
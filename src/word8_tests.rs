@@ -0,0 +1,142 @@
+use crate::cpu_component::{
+    INTERRUPT_VECTOR_TABLE, PROGRAM_COUNTER_REG_NUM, REGISTERS_NUM, ZERO_DIVISOR_BIT_NUM,
+};
+use crate::debugger::Debugger;
+use crate::gdb::GdbStub;
+use crate::interrupts::{Exception, IRQ_VECTOR_BASE, PIC_MASK_PORT};
+use crate::microcodes::INSTRUCTION::*;
+use crate::microcodes::{
+    instruction_words, INSTRUCTION, DEST_SHIFT, INSTRUCTION_WORDS, OPCODE_SHIFT, REG,
+    SOURCE_SHIFT,
+};
+use crate::{Engine, MachineBuilder, MachineStatus};
+
+fn encode(op: INSTRUCTION, src: REG, dst: REG) -> [u32; INSTRUCTION_WORDS] {
+    instruction_words(
+        (op as u32) << OPCODE_SHIFT | (src as u32) << SOURCE_SHIFT | (dst as u32) << DEST_SHIFT,
+    )
+}
+
+fn ldcnst(dst: REG, value: u32) -> Vec<u32> {
+    [&encode(LDCNST, REG::A, dst)[..], &[value]].concat()
+}
+
+/// Adds 5 + 4 + 3 + 2 + 1 into e through a call.
+fn sum_program() -> Vec<u32> {
+    [
+        &ldcnst(REG::C, 5)[..],           // 0
+        &ldcnst(REG::D, 22),              // 3: loop
+        &encode(CALL, REG::A, REG::D),    // 6
+        &encode(DEC, REG::A, REG::C),     // 8
+        &encode(MOV, REG::C, REG::A),     // 10
+        &ldcnst(REG::B, 0),               // 12
+        &ldcnst(REG::D, 3),               // 15
+        &encode(JNE, REG::A, REG::D),     // 18
+        &encode(HLT, REG::A, REG::A),     // 20
+        &encode(MOV, REG::E, REG::A),     // 22: adds c to e
+        &encode(MOV, REG::C, REG::B),     // 24
+        &encode(ADD, REG::A, REG::E),     // 26
+        &encode(POP, REG::A, REG::PC),    // 28
+    ]
+    .concat()
+}
+
+#[test]
+fn two_word_instruction_test() {
+    let program = sum_program();
+    for engine in [Engine::Threaded, Engine::Sequential] {
+        let mut m = MachineBuilder::new().image(&program).engine(engine).build();
+        // the first instruction only fetches, both of its words
+        m.step_instruction();
+        assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 2);
+        assert_eq!(m.next_address(), 0);
+        assert_eq!(m.instruction(), m.read_instruction(0));
+        assert_ne!(m.instruction(), m.register(REG::INST as usize));
+        assert_eq!(m.run(), MachineStatus::Halted);
+        assert_eq!(m.register(REG::E as usize), 15);
+        assert_eq!(m.register(PROGRAM_COUNTER_REG_NUM), 22);
+    }
+    let mut interpreter = MachineBuilder::new().image(&program).build_interpreter();
+    assert_eq!(interpreter.run(), MachineStatus::Halted);
+    assert_eq!(interpreter.register(REG::E as usize), 15);
+    assert_eq!(interpreter.register(PROGRAM_COUNTER_REG_NUM), 22);
+
+    let mut debugger = Debugger::new(MachineBuilder::new().image(&program).build());
+    let listing = debugger.execute("disas 3").unwrap();
+    assert!(listing.starts_with("=> 0: ldcnst a c\n   2: constant 5\n   3: ldcnst a d"));
+    assert!(listing.ends_with("   6: call a d"));
+}
+
+#[test]
+fn two_word_irq_test() {
+    let program = [
+        &ldcnst(REG::A, PIC_MASK_PORT)[..], // 0
+        &ldcnst(REG::B, 0),                 // 3
+        &encode(OUT, REG::A, REG::B),       // 6
+        &encode(INC, REG::A, REG::C),       // 8
+        &encode(HLT, REG::A, REG::A),       // 10
+        &encode(INC, REG::A, REG::E),       // 12: line 0
+        &encode(EOI, REG::A, REG::A),       // 14
+    ]
+    .concat();
+    let vector = (INTERRUPT_VECTOR_TABLE + IRQ_VECTOR_BASE) as u32;
+    let mut m = MachineBuilder::new().image(&program).build();
+    let mut interpreter = MachineBuilder::new().image(&program).build_interpreter();
+    m.write_ram(vector, 12);
+    interpreter.write_ram(vector, 12);
+    m.interrupt_controller().raise(0);
+    interpreter.interrupt_controller().raise(0);
+    assert_eq!(m.run(), MachineStatus::Halted);
+    assert_eq!(interpreter.run(), MachineStatus::Halted);
+    // served once unmasked, then returned to the inc
+    for r in 0..REGISTERS_NUM {
+        assert_eq!(m.register(r), interpreter.register(r), "register {}", r);
+    }
+    assert_eq!(m.register(REG::C as usize), 1);
+    assert_eq!(m.register(REG::E as usize), 1);
+}
+
+#[test]
+fn wide_flags_test() {
+    let program = [
+        &ldcnst(REG::A, 7)[..],
+        &ldcnst(REG::B, 0),
+        &encode(DIV, REG::A, REG::C),
+        &encode(HLT, REG::A, REG::A),
+    ]
+    .concat();
+    let mut m = MachineBuilder::new().image(&program).build();
+    assert_eq!(m.run(), MachineStatus::Faulted(Exception::DivideError));
+    // the zero divisor flag doesn't fit into a word
+    assert_ne!(m.flags() & (1 << ZERO_DIVISOR_BIT_NUM), 0);
+}
+
+#[test]
+fn two_word_snapshot_test() {
+    let mut m = MachineBuilder::new().image(&sum_program()).build();
+    m.step_instruction();
+    // ldcnst, then the first word of the next fetch
+    for _ in 0..4 {
+        m.step_cycle();
+    }
+    assert!(!m.at_instruction_boundary());
+    let snapshot = m.snapshot();
+    let mut restored = MachineBuilder::new().engine(Engine::Sequential).build();
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(m.run(), MachineStatus::Halted);
+    assert_eq!(restored.run(), MachineStatus::Halted);
+    assert!(m.snapshot().diff(&restored.snapshot()).is_empty());
+    assert_eq!(restored.register(REG::E as usize), 15);
+}
+
+#[test]
+fn two_word_breakpoint_test() {
+    let mut stub = GdbStub::new(MachineBuilder::new().image(&sum_program()).build());
+    // on the second word of the call, it never matches
+    assert_eq!(stub.handle("Z0,7,2").unwrap(), "OK");
+    assert_eq!(stub.handle("Z0,6,2").unwrap(), "OK");
+    assert_eq!(stub.handle("c").unwrap(), "S05");
+    assert_eq!(stub.handle("p5").unwrap(), "06");
+    assert_eq!(stub.handle("z0,6,2").unwrap(), "OK");
+    assert_eq!(stub.handle("c").unwrap(), "W00");
+}